@@ -0,0 +1,204 @@
+//! Settings for a merge, read from a TOML or JSON file.
+
+use std::fs;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::merge::Strategy;
+use crate::GlobalFormId;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// How records are merged, such as `three-way`.
+    pub strategy: Strategy,
+    /// Plugins whose music records are left out of the merge.
+    pub exclude_plugins: Vec<String>,
+    /// Rules for particular records, applied in order.
+    pub rules: Vec<Rule>,
+}
+
+/// Settings for the records matching `editor_id` and `form_id`. A rule
+/// needs at least one of them, and matches records matching both.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rule {
+    /// What reports call the rule. Defaults to its position, as `rule 1`.
+    pub name: Option<String>,
+    pub editor_id: Option<String>,
+    pub form_id: Option<GlobalFormId>,
+    /// Leave the record out of the patch.
+    pub skip: bool,
+    /// Merge the record with this strategy instead.
+    pub strategy: Option<Strategy>,
+    /// Only merge these plugins' versions, and only keep tracks they list.
+    pub only_plugins: Vec<String>,
+    /// Tracks to add to the merged record.
+    pub append_tracks: Vec<GlobalFormId>,
+}
+
+impl Rule {
+    pub fn matches(&self, form_id: &GlobalFormId, editor_id: &str) -> bool {
+        let editor_id_matches = match self.editor_id {
+            Some(ref rule_editor_id) => rule_editor_id.eq_ignore_ascii_case(editor_id),
+            None => true,
+        };
+        let form_id_matches = match self.form_id {
+            Some(ref rule_form_id) => rule_form_id == form_id,
+            None => true,
+        };
+        editor_id_matches && form_id_matches
+    }
+}
+
+impl Config {
+    /// Read a config file, as JSON if it ends in `.json` and TOML otherwise.
+    pub fn read(p: &Path) -> Result<Self, Error> {
+        let text = fs::read_to_string(p)?;
+        let is_json = p
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+        let config: Config = if is_json {
+            serde_json::from_str(&text).map_err(|e| Error::new(ErrorKind::InvalidData, e))?
+        } else {
+            toml::from_str(&text).map_err(|e| Error::new(ErrorKind::InvalidData, e))?
+        };
+        for (index, rule) in config.rules.iter().enumerate() {
+            if rule.editor_id.is_none() && rule.form_id.is_none() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("rule {} needs an editor_id or a form_id", index + 1),
+                ));
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn is_excluded(&self, plugin: &str) -> bool {
+        self.exclude_plugins
+            .iter()
+            .any(|excluded| excluded.eq_ignore_ascii_case(plugin))
+    }
+
+    /// The rules matching a record, with their names.
+    pub fn rules_for(&self, form_id: &GlobalFormId, editor_id: &str) -> Vec<(String, &Rule)> {
+        self.rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| rule.matches(form_id, editor_id))
+            .map(|(index, rule)| {
+                let name = match rule.name {
+                    Some(ref name) => name.clone(),
+                    None => format!("rule {}", index + 1),
+                };
+                (name, rule)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const TOML: &str = r#"
+strategy = "union"
+exclude_plugins = ["Unwanted.esp"]
+
+[[rules]]
+name = "no boss music"
+editor_id = "MUSCombatBoss"
+skip = true
+
+[[rules]]
+form_id = "Skyrim.esm:000101"
+strategy = "prefer:Dungeons.esp"
+only_plugins = ["Dungeons.esp"]
+append_tracks = ["Tracks.esp:000800"]
+"#;
+
+    const JSON: &str = r#"{
+    "strategy": "union",
+    "exclude_plugins": ["Unwanted.esp"],
+    "rules": [
+        { "name": "no boss music", "editor_id": "MUSCombatBoss", "skip": true },
+        {
+            "form_id": "Skyrim.esm:000101",
+            "strategy": "prefer:Dungeons.esp",
+            "only_plugins": ["Dungeons.esp"],
+            "append_tracks": ["Tracks.esp:000800"]
+        }
+    ]
+}"#;
+
+    fn read_temp(name: &str, contents: &str) -> Result<Config, Error> {
+        let dir = TempDir::new();
+        Config::read(&dir.write(name, contents))
+    }
+
+    #[test]
+    fn reads_toml_and_json_alike() {
+        for config in [
+            read_temp("config.toml", TOML).unwrap(),
+            read_temp("config.JSON", JSON).unwrap(),
+        ] {
+            assert_eq!(config.strategy, Strategy::Union);
+            assert!(config.is_excluded("unwanted.esp"));
+            assert!(!config.is_excluded("Dungeons.esp"));
+            assert_eq!(config.rules.len(), 2);
+
+            let boss = &config.rules[0];
+            assert_eq!(boss.name.as_deref(), Some("no boss music"));
+            assert_eq!(boss.editor_id.as_deref(), Some("MUSCombatBoss"));
+            assert!(boss.skip);
+
+            let dungeon = &config.rules[1];
+            assert_eq!(
+                dungeon.form_id,
+                Some(GlobalFormId::new("Skyrim.esm", 0x101))
+            );
+            assert_eq!(
+                dungeon.strategy,
+                Some(Strategy::PreferPlugin(String::from("Dungeons.esp")))
+            );
+            assert_eq!(dungeon.only_plugins, ["Dungeons.esp"]);
+            assert_eq!(
+                dungeon.append_tracks,
+                [GlobalFormId::new("Tracks.esp", 0x800)]
+            );
+            assert!(!dungeon.skip);
+        }
+    }
+
+    #[test]
+    fn names_rules_by_position() {
+        let config = read_temp("config.toml", TOML).unwrap();
+        let names = |form_id: u32, editor_id: &str| -> Vec<String> {
+            config
+                .rules_for(&GlobalFormId::new("SKYRIM.ESM", form_id), editor_id)
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect()
+        };
+        assert_eq!(names(0x100, "muscombatboss"), ["no boss music"]);
+        assert_eq!(names(0x101, "MUSDungeon"), ["rule 2"]);
+        assert!(names(0x102, "MUSTavern").is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        for (name, contents) in [
+            ("empty_rule.toml", "[[rules]]\nskip = true\n"),
+            ("bad_form_id.toml", "[[rules]]\nform_id = \"Skyrim.esm\"\n"),
+            ("bad_strategy.toml", "strategy = \"newest\"\n"),
+            ("unknown_field.toml", "strategies = \"union\"\n"),
+            ("empty_rule.json", r#"{ "rules": [{ "name": "nothing" }] }"#),
+        ] {
+            assert!(read_temp(name, contents).is_err(), "{}", name);
+        }
+    }
+}
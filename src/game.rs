@@ -1,6 +1,16 @@
+use std::io::Error;
+use std::path::PathBuf;
+
 use std::fmt;
+use std::str::FromStr;
 
-#[derive(Debug, Clone)]
+use crate::find_file_ignore_case;
+use crate::GameLocator;
+use crate::InstalledGame;
+use crate::LoadOrderEntry;
+use crate::LoadOrderMethod;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Game {
     Morrowind,
     Oblivion,
@@ -8,6 +18,7 @@ pub enum Game {
     SkyrimSE,
     SkyrimVR,
     Fallout3,
+    FalloutNV,
     Fallout4,
     Fallout4VR,
 }
@@ -21,54 +32,305 @@ impl fmt::Display for Game {
             Self::SkyrimSE => "Skyrim Special Edition",
             Self::SkyrimVR => "Skyrim VR",
             Self::Fallout3 => "Fallout 3",
+            Self::FalloutNV => "Fallout New Vegas",
             Self::Fallout4 => "Fallout 4",
             Self::Fallout4VR => "Fallout 4 VR",
         })
     }
 }
 
+impl FromStr for Game {
+    type Err = String;
+
+    /// Accepts either the game's ID or its full name, ignoring case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|game| {
+                game.id().eq_ignore_ascii_case(s) || game.to_string().eq_ignore_ascii_case(s)
+            })
+            .copied()
+            .ok_or_else(|| {
+                let ids: Vec<&str> = Self::ALL.iter().map(|game| game.id()).collect();
+                format!("Unknown game `{}`. Expected one of: {}", s, ids.join(", "))
+            })
+    }
+}
+
 impl Game {
+    pub const ALL: [Game; 9] = [
+        Self::Morrowind,
+        Self::Oblivion,
+        Self::Skyrim,
+        Self::SkyrimSE,
+        Self::SkyrimVR,
+        Self::Fallout3,
+        Self::FalloutNV,
+        Self::Fallout4,
+        Self::Fallout4VR,
+    ];
+
     // Skyrim and Fallout 4 don't have their core esm files
     // listed in the load order, so we use this to include them.
     pub fn implicit_modules(&self) -> Vec<String> {
         match self {
-            Self::Skyrim | Self::SkyrimSE | Self::SkyrimVR => vec![
+            Self::Skyrim => vec![
                 "Skyrim.esm".to_string(),
                 "Update.esm".to_string(),
             ],
+            // The DLC is part of the base game from Special Edition onwards.
+            Self::SkyrimSE => vec![
+                "Skyrim.esm".to_string(),
+                "Update.esm".to_string(),
+                "Dawnguard.esm".to_string(),
+                "HearthFires.esm".to_string(),
+                "Dragonborn.esm".to_string(),
+            ],
+            Self::SkyrimVR => vec![
+                "Skyrim.esm".to_string(),
+                "Update.esm".to_string(),
+                "Dawnguard.esm".to_string(),
+                "HearthFires.esm".to_string(),
+                "Dragonborn.esm".to_string(),
+                "SkyrimVR.esm".to_string(),
+            ],
             Self::Fallout4 | Self::Fallout4VR => vec![
                 "Fallout4.esm".to_string(),
             ],
             _ => vec![],
         }
     }
+
+    /// Name of the folder under `%LOCALAPPDATA%` holding `plugins.txt`.
+    /// Morrowind keeps everything in its install directory instead.
+    pub fn local_folder_name(&self) -> Option<&'static str> {
+        match self {
+            Self::Morrowind => None,
+            Self::Oblivion => Some("Oblivion"),
+            Self::Skyrim => Some("Skyrim"),
+            Self::SkyrimSE => Some("Skyrim Special Edition"),
+            Self::SkyrimVR => Some("Skyrim VR"),
+            Self::Fallout3 => Some("Fallout3"),
+            Self::FalloutNV => Some("FalloutNV"),
+            Self::Fallout4 => Some("Fallout4"),
+            Self::Fallout4VR => Some("Fallout4VR"),
+        }
+    }
+
+    /// Short name used to pick a game on the command line.
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::Morrowind => "morrowind",
+            Self::Oblivion => "oblivion",
+            Self::Skyrim => "skyrim",
+            Self::SkyrimSE => "skyrimse",
+            Self::SkyrimVR => "skyrimvr",
+            Self::Fallout3 => "fallout3",
+            Self::FalloutNV => "falloutnv",
+            Self::Fallout4 => "fallout4",
+            Self::Fallout4VR => "fallout4vr",
+        }
+    }
+
+    /// Registry keys under `HKEY_LOCAL_MACHINE` holding the game's
+    /// "installed path". 32 bit games on 64 bit Windows are redirected
+    /// to `WOW6432Node`.
+    pub fn registry_keys(&self) -> Vec<String> {
+        let name = match self {
+            Self::Morrowind => "Morrowind",
+            Self::Oblivion => "Oblivion",
+            Self::Skyrim => "skyrim",
+            Self::SkyrimSE => "Skyrim Special Edition",
+            Self::SkyrimVR => "Skyrim VR",
+            Self::Fallout3 => "Fallout3",
+            Self::FalloutNV => "FalloutNV",
+            Self::Fallout4 => "Fallout4",
+            Self::Fallout4VR => "Fallout 4 VR",
+        };
+        vec![
+            format!("SOFTWARE\\WOW6432NODE\\Bethesda Softworks\\{}", name),
+            format!("SOFTWARE\\Bethesda Softworks\\{}", name),
+        ]
+    }
+
+    /// GOG's product IDs for the game, which name its registry key.
+    pub fn gog_ids(&self) -> &'static [u32] {
+        match self {
+            Self::Morrowind => &[1435828767],
+            Self::Oblivion => &[1458058109],
+            Self::SkyrimSE => &[1711230643],
+            Self::Fallout3 => &[1454315831],
+            Self::FalloutNV => &[1454587428],
+            Self::Fallout4 => &[1998527297],
+            Self::Skyrim | Self::SkyrimVR | Self::Fallout4VR => &[],
+        }
+    }
+
+    /// Steam's app IDs for the game, which name its app manifest and
+    /// Proton prefix. Some games were sold under more than one.
+    pub fn steam_app_ids(&self) -> &'static [u32] {
+        match self {
+            Self::Morrowind => &[22320],
+            Self::Oblivion => &[22330],
+            Self::Skyrim => &[72850],
+            Self::SkyrimSE => &[489830],
+            Self::SkyrimVR => &[611670],
+            Self::Fallout3 => &[22300, 22370],
+            Self::FalloutNV => &[22380],
+            Self::Fallout4 => &[377160],
+            Self::Fallout4VR => &[611660],
+        }
+    }
+
+    /// Name of the game's folder under `steamapps/common`.
+    pub fn steam_folder_name(&self) -> &'static str {
+        match self {
+            Self::Morrowind => "Morrowind",
+            Self::Oblivion => "Oblivion",
+            Self::Skyrim => "Skyrim",
+            Self::SkyrimSE => "Skyrim Special Edition",
+            Self::SkyrimVR => "SkyrimVR",
+            Self::Fallout3 => "Fallout 3",
+            Self::FalloutNV => "Fallout New Vegas",
+            Self::Fallout4 => "Fallout 4",
+            Self::Fallout4VR => "Fallout 4 VR",
+        }
+    }
+
+    /// Name of the folder under the install directory holding the plugins.
+    pub fn data_folder_name(&self) -> &'static str {
+        match self {
+            Self::Morrowind => "Data Files",
+            _ => "Data",
+        }
+    }
+
+    /// Whether the game's MUSC records are laid out the way we read and
+    /// write them. Fallout 3 and New Vegas store a file name and an ANAM
+    /// instead of the flags, priority, fade and track list Skyrim and
+    /// Fallout 4 have.
+    pub fn supports_music_merge(&self) -> bool {
+        matches!(
+            self,
+            Self::Skyrim | Self::SkyrimSE | Self::SkyrimVR | Self::Fallout4 | Self::Fallout4VR
+        )
+    }
+
+    /// Whether the game understands light plugins (`.esl` files and
+    /// plugins with the light flag set).
+    pub fn supports_light_plugins(&self) -> bool {
+        matches!(self, Self::SkyrimSE | Self::Fallout4)
+    }
+
+    /// The HEDR version plugins for this game are saved with.
+    pub fn plugin_version(&self) -> f32 {
+        match self {
+            Self::Morrowind => 1.3,
+            Self::Oblivion | Self::Fallout4 => 1.0,
+            Self::Skyrim | Self::Fallout3 => 0.94,
+            Self::FalloutNV => 1.34,
+            Self::SkyrimSE | Self::SkyrimVR => 1.7,
+            Self::Fallout4VR => 0.95,
+        }
+    }
+
+    /// The form version stored in the header of every record saved by
+    /// this game's Creation Kit. Morrowind and Oblivion records don't have
+    /// one.
+    pub fn form_version(&self) -> u16 {
+        match self {
+            Self::Morrowind | Self::Oblivion => 0,
+            Self::Fallout3 | Self::FalloutNV => 15,
+            Self::Skyrim => 43,
+            Self::SkyrimSE | Self::SkyrimVR => 44,
+            Self::Fallout4 | Self::Fallout4VR => 131,
+        }
+    }
+
+    /// How the engine decides the order plugins load in. Games before
+    /// Skyrim Special Edition go by file modification time, regardless of
+    /// what tools write to loadorder.txt.
+    pub fn load_order_method(&self) -> LoadOrderMethod {
+        match self {
+            Self::SkyrimSE | Self::SkyrimVR | Self::Fallout4 | Self::Fallout4VR => {
+                LoadOrderMethod::Asterisk
+            }
+            Self::Morrowind | Self::Oblivion | Self::Skyrim | Self::Fallout3 | Self::FalloutNV => {
+                LoadOrderMethod::Timestamp
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct GameSettings {
+    pub game: Game,
     pub id: String,
     pub name: String,
     pub location: String,
+    /// Directory the game loads plugins from.
+    pub data_location: String,
+    pub local_location: Option<String>,
+    /// plugins.txt to read instead of the one in `local_location`.
+    pub plugins_file: Option<String>,
     pub implicit_modules: Vec<String>,
-    pub load_order: Vec<String>,
+    pub load_order_method: LoadOrderMethod,
+    pub load_order: Vec<LoadOrderEntry>,
+    /// Directories a mod manager layers over the data directory,
+    /// highest priority first.
+    pub data_overlays: Vec<PathBuf>,
 }
 
 impl GameSettings {
-    pub fn new<S>(game: Game, location: S) -> Self
-    where
-        S: Into<String>,
-    {
+    pub fn new(installed: &InstalledGame) -> Self {
+        let game = installed.game;
         GameSettings {
-            id: format!("{}", game), // TODO: Figure out why I have this
+            game,
+            id: String::from(game.id()),
             name: format!("{}", game),
-            location: location.into(),
+            location: installed.install_path.to_string_lossy().into_owned(),
+            data_location: installed.data_path.to_string_lossy().into_owned(),
+            local_location: installed
+                .local_path
+                .as_ref()
+                .map(|p| p.to_string_lossy().into_owned()),
+            plugins_file: None,
             implicit_modules: game.implicit_modules(),
+            load_order_method: game.load_order_method(),
             load_order: vec![],
+            data_overlays: vec![],
         }
     }
-    // Note that Morrowind includes its load order in Morrwind.ini
-    // in the base install directory!
-    pub fn read_load_order() {
-        unimplemented!()
+
+    /// Find the game with the default `GameLocator`.
+    pub fn locate(game: Game) -> Result<Self, String> {
+        GameLocator::new()
+            .locate(game)
+            .map(|installed| Self::new(&installed))
+            .ok_or_else(|| format!("Unable to find {}.", game))
+    }
+
+    /// Directory the game loads plugins from.
+    pub fn data_path(&self) -> PathBuf {
+        PathBuf::from(&self.data_location)
+    }
+
+    /// The file the game will load for a plugin, looking through any
+    /// overlays before the data directory.
+    pub fn plugin_path(&self, name: &str) -> PathBuf {
+        for overlay in self.data_overlays.iter() {
+            let path = find_file_ignore_case(overlay, name);
+            if path.is_file() {
+                return path;
+            }
+        }
+        find_file_ignore_case(&self.data_path(), name)
+    }
+
+    // Note that Morrowind includes its load order in Morrowind.ini
+    // in the base install directory, which `read_load_order` handles.
+    pub fn read_load_order(&mut self) -> Result<(), Error> {
+        self.load_order = crate::read_load_order(self)?;
+        Ok(())
     }
 }
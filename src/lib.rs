@@ -1,19 +1,52 @@
 mod game;
 pub use game::*;
-use winreg::RegKey;
+
+mod load_order;
+pub use load_order::*;
+
+pub mod mo2;
 
 mod parser;
 pub use parser::*;
 
-pub fn read_load_order() -> Result<Vec<String>, ()> {
-    let mut list: Vec<String> = vec![];
-    unimplemented!()
-}
+mod locator;
+pub use locator::*;
 
-pub fn registry_install_path(key: String) -> Result<String, String> {
+pub mod config;
+
+pub mod merge;
+
+pub mod report;
+
+pub mod steam;
+
+pub mod vortex;
+
+pub mod wine;
+
+#[cfg(test)]
+mod test_util;
+
+#[cfg(windows)]
+pub fn registry_path(key: &str, name: &str) -> Result<String, String> {
+    use winreg::RegKey;
     RegKey::predef(winreg::enums::HKEY_LOCAL_MACHINE)
         .open_subkey_with_flags(key, winreg::enums::KEY_READ)
         .map_err(|e| e.to_string())?
-        .get_value("installed path")
+        .get_value(name)
         .map_err(|e| e.to_string())
 }
+
+// Outside of Windows, the closest thing to the system registry is that
+// of the default Wine prefix.
+#[cfg(not(windows))]
+pub fn registry_path(key: &str, name: &str) -> Result<String, String> {
+    match wine::default_prefix() {
+        Some(prefix) => wine::prefix_registry_path(&prefix, key, name),
+        None => Err(format!("Unable to read `{}`: no Wine prefix found", key)),
+    }
+}
+
+pub fn registry_install_path(key: String) -> Result<String, String> {
+    registry_path(&key, "installed path")
+}
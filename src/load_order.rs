@@ -0,0 +1,452 @@
+use std::fs;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use log::debug;
+
+use crate::parse_header;
+use crate::Game;
+use crate::GameSettings;
+use crate::GlobalFormId;
+use crate::Plugin;
+
+/// How a game decides the order plugins are loaded in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadOrderMethod {
+    /// plugins.txt lists every plugin in order, with active ones prefixed by `*`.
+    Asterisk,
+    /// loadorder.txt lists every plugin in order and plugins.txt lists the
+    /// active ones. No game reads loadorder.txt itself, so
+    /// `Game::load_order_method` never returns this. It's for mod managers
+    /// that order older games by loadorder.txt and set timestamps to match,
+    /// as Mod Organizer 2 does.
+    Textfile,
+    /// Plugins load by modification time, masters first. plugins.txt lists
+    /// the active ones.
+    Timestamp,
+}
+
+/// A plugin in the load order, in the order the game will load it.
+#[derive(Debug, Clone)]
+pub struct LoadOrderEntry {
+    pub name: String,
+    pub active: bool,
+    pub master: bool,
+    /// Light plugins share the `FE` index rather than taking a slot of their own.
+    pub light: bool,
+}
+
+impl LoadOrderEntry {
+    pub fn new<S>(name: S, active: bool) -> Self
+    where
+        S: Into<String>,
+    {
+        LoadOrderEntry {
+            name: name.into(),
+            active,
+            master: false,
+            light: false,
+        }
+    }
+}
+
+/// Read the entries of a plugins.txt/loadorder.txt style file, skipping
+/// blank lines and `#` comments. Older games write these files in the
+/// system codepage, so anything that isn't UTF-8 is converted lossily.
+fn read_list_file(p: &Path) -> Result<Vec<String>, Error> {
+    let file = File::open(p)?;
+    let mut reader = BufReader::new(file);
+    let mut lines: Vec<String> = vec![];
+    let mut buf: Vec<u8> = vec![];
+    while reader.read_until(b'\n', &mut buf)? > 0 {
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.trim_start_matches('\u{feff}').trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            lines.push(String::from(line));
+        }
+        buf.clear();
+    }
+    Ok(lines)
+}
+
+/// Read a plugins.txt file. With `asterisks`, every plugin is listed and
+/// only those prefixed with `*` are active. Otherwise only active plugins
+/// are listed.
+pub fn read_plugins_file(p: &Path, asterisks: bool) -> Result<Vec<LoadOrderEntry>, Error> {
+    Ok(read_list_file(p)?
+        .into_iter()
+        .filter_map(|line| match line.strip_prefix('*') {
+            // A lone `*` doesn't name a plugin.
+            Some(name) if asterisks => Some(name.trim())
+                .filter(|name| !name.is_empty())
+                .map(|name| LoadOrderEntry::new(name, true)),
+            _ => Some(LoadOrderEntry::new(line, !asterisks)),
+        })
+        .collect())
+}
+
+/// Read a loadorder.txt file, which lists every plugin regardless of
+/// whether it is active.
+pub fn read_loadorder_file(p: &Path) -> Result<Vec<String>, Error> {
+    read_list_file(p)
+}
+
+/// Read the active plugins from the `[Game Files]` section of Morrowind.ini,
+/// which lists them as `GameFile0=Morrowind.esm`, `GameFile1=...` and so on.
+pub fn read_morrowind_ini(p: &Path) -> Result<Vec<String>, Error> {
+    let mut in_game_files = false;
+    let mut files: Vec<(u32, String)> = vec![];
+    for line in read_list_file(p)? {
+        if line.starts_with('[') {
+            in_game_files = line.eq_ignore_ascii_case("[Game Files]");
+        } else if in_game_files && !line.starts_with(';') {
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+            let index = key
+                .get(..8)
+                .filter(|prefix| prefix.eq_ignore_ascii_case("GameFile"))
+                .and_then(|_| key[8..].parse::<u32>().ok());
+            if let Some(index) = index {
+                if !value.is_empty() {
+                    files.push((index, String::from(value)));
+                }
+            }
+        }
+    }
+    files.sort_by_key(|(index, _)| *index);
+    Ok(files.into_iter().map(|(_, name)| name).collect())
+}
+
+/// Find `name` in `dir`, ignoring case. The game doesn't care about case,
+/// so plugins.txt often disagrees with the files on disk, which matters
+/// on any filesystem that isn't Windows'.
+pub fn find_file_ignore_case(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if path.exists() {
+        return path;
+    }
+    if let Ok(dir_entries) = fs::read_dir(dir) {
+        for dir_entry in dir_entries.flatten() {
+            if dir_entry
+                .file_name()
+                .to_string_lossy()
+                .eq_ignore_ascii_case(name)
+            {
+                return dir_entry.path();
+            }
+        }
+    }
+    path
+}
+
+/// Plugin names are case insensitive, as is the filesystem they live on.
+fn find_entry<'a>(entries: &'a [LoadOrderEntry], name: &str) -> Option<&'a LoadOrderEntry> {
+    entries.iter().find(|e| e.name.eq_ignore_ascii_case(name))
+}
+
+fn has_extension(name: &str, extensions: &[&str]) -> bool {
+    match name.rsplit_once('.') {
+        Some((_, ext)) => extensions.iter().any(|e| ext.eq_ignore_ascii_case(e)),
+        None => false,
+    }
+}
+
+fn is_plugin_file(name: &str) -> bool {
+    has_extension(name, &["esm", "esp"])
+}
+
+fn plugins_file_path(settings: &GameSettings) -> Result<PathBuf, Error> {
+    if let Some(ref plugins_file) = settings.plugins_file {
+        return Ok(PathBuf::from(plugins_file));
+    }
+    match settings.local_location {
+        Some(ref local_location) => Ok(find_file_ignore_case(
+            Path::new(local_location),
+            "plugins.txt",
+        )),
+        None => Err(Error::new(
+            ErrorKind::NotFound,
+            format!("{} has no plugins.txt", settings.name),
+        )),
+    }
+}
+
+/// Read the plugins the game has been told to load. Everything but
+/// Morrowind keeps these in plugins.txt.
+fn read_active_plugins(
+    settings: &GameSettings,
+    asterisks: bool,
+) -> Result<Vec<LoadOrderEntry>, Error> {
+    if settings.game == Game::Morrowind {
+        let ini_path = find_file_ignore_case(Path::new(&settings.location), "Morrowind.ini");
+        return Ok(read_morrowind_ini(&ini_path)?
+            .into_iter()
+            .map(|name| LoadOrderEntry::new(name, true))
+            .collect());
+    }
+    read_plugins_file(&plugins_file_path(settings)?, asterisks)
+}
+
+fn implicit_entries(settings: &GameSettings) -> Vec<LoadOrderEntry> {
+    settings
+        .implicit_modules
+        .iter()
+        .map(|name| LoadOrderEntry::new(name.as_str(), true))
+        .collect()
+}
+
+/// Append `names` to `entries`, skipping any already present. A plugin is
+/// active if it is listed in `active`.
+fn append_entries<I>(entries: &mut Vec<LoadOrderEntry>, names: I, active: &[LoadOrderEntry])
+where
+    I: IntoIterator<Item = String>,
+{
+    for name in names {
+        if find_entry(entries, &name).is_none() {
+            let is_active = find_entry(active, &name).is_some_and(|e| e.active);
+            entries.push(LoadOrderEntry::new(name, is_active));
+        }
+    }
+}
+
+/// Order the plugins in the data directory, plus any listed as active, by
+/// modification time. Plugins we can't find sort last.
+fn sort_by_timestamp(data_path: &Path, active: &[LoadOrderEntry]) -> Result<Vec<String>, Error> {
+    let mut plugins: Vec<(String, Option<SystemTime>)> = vec![];
+    for dir_entry in fs::read_dir(data_path)? {
+        let dir_entry = dir_entry?;
+        let name = dir_entry.file_name().to_string_lossy().into_owned();
+        if is_plugin_file(&name) && dir_entry.file_type()?.is_file() {
+            plugins.push((name, dir_entry.metadata()?.modified().ok()));
+        }
+    }
+    for entry in active {
+        if !plugins
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case(&entry.name))
+        {
+            plugins.push((entry.name.clone(), None));
+        }
+    }
+    plugins.sort_by(|(a_name, a_time), (b_name, b_time)| {
+        a_time
+            .is_none()
+            .cmp(&b_time.is_none())
+            .then(a_time.cmp(b_time))
+            .then_with(|| a_name.to_lowercase().cmp(&b_name.to_lowercase()))
+    });
+    Ok(plugins.into_iter().map(|(name, _)| name).collect())
+}
+
+/// Fill in the master and light flags of each entry from its plugin header.
+/// Plugins we can't read fall back to what their extension implies.
+fn read_plugin_flags(settings: &GameSettings, entries: &mut [LoadOrderEntry]) {
+    let supports_light = settings.game.supports_light_plugins();
+    for entry in entries.iter_mut().filter(|entry| !entry.name.is_empty()) {
+        let plugin = match parse_header(&settings.plugin_path(&entry.name)) {
+            Ok(plugin) => plugin,
+            Err(err) => {
+                debug!("{}: unable to read the header: {}", entry.name, err);
+                Plugin::new(Path::new(&entry.name))
+            }
+        };
+        entry.master = plugin.is_master();
+        entry.light = supports_light && plugin.is_light();
+    }
+}
+
+/// Read the full load order for a game, active and inactive plugins alike.
+///
+/// Implicitly loaded masters always come first. The rest are ordered
+/// according to the settings' `load_order_method`, except that the engine
+/// always loads masters (including light masters) before other plugins.
+pub fn read_load_order(settings: &GameSettings) -> Result<Vec<LoadOrderEntry>, Error> {
+    let mut entries = implicit_entries(settings);
+    match settings.load_order_method {
+        LoadOrderMethod::Asterisk => {
+            let listed = read_active_plugins(settings, true)?;
+            append_entries(&mut entries, listed.iter().map(|e| e.name.clone()), &listed);
+        }
+        LoadOrderMethod::Textfile => {
+            let plugins_path = plugins_file_path(settings)?;
+            let listed = read_plugins_file(&plugins_path, false)?;
+            let loadorder_path = find_file_ignore_case(
+                plugins_path.parent().unwrap_or(Path::new("")),
+                "loadorder.txt",
+            );
+            if loadorder_path.exists() {
+                append_entries(&mut entries, read_loadorder_file(&loadorder_path)?, &listed);
+            }
+            append_entries(&mut entries, listed.iter().map(|e| e.name.clone()), &listed);
+        }
+        LoadOrderMethod::Timestamp => {
+            let listed = read_active_plugins(settings, false)?;
+            let sorted = sort_by_timestamp(&settings.data_path(), &listed)?;
+            append_entries(&mut entries, sorted, &listed);
+        }
+    }
+    read_plugin_flags(settings, &mut entries);
+    entries.sort_by_key(|e| !e.master);
+    Ok(entries)
+}
+
+/// The FormID the game will assign `id` at runtime, given the plugins in
+/// `load_order`. Full plugins take the indexes `00` to `FD` in order,
+/// while light plugins are packed into `FE`, each with a 12 bit slot.
+/// Returns `None` if the defining plugin isn't active.
+pub fn runtime_form_id(load_order: &[LoadOrderEntry], id: &GlobalFormId) -> Option<u32> {
+    let mut full_index: u32 = 0;
+    let mut light_index: u32 = 0;
+    for entry in load_order.iter().filter(|e| e.active) {
+        if entry.name.eq_ignore_ascii_case(&id.plugin) {
+            return Some(if entry.light {
+                0xFE00_0000 | (light_index << 12) | (id.object_id & 0xFFF)
+            } else {
+                (full_index << 24) | id.object_id
+            });
+        }
+        if entry.light {
+            light_index += 1;
+        } else {
+            full_index += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_util::TempDir;
+    use crate::{InstallSource, InstalledGame};
+
+    #[test]
+    fn lone_asterisks_are_not_plugins() {
+        let dir = TempDir::new();
+        let path = dir.write("plugins.txt", "# comment\n*Skyrim.esm\n*\n* \nFoo.esp\n");
+        let entries = read_plugins_file(&path, true).unwrap();
+        let entries: Vec<(&str, bool)> = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.active))
+            .collect();
+        assert_eq!(entries, [("Skyrim.esm", true), ("Foo.esp", false)]);
+    }
+
+    #[test]
+    fn reads_morrowind_ini_game_files_in_order() {
+        let dir = TempDir::new();
+        let path = dir.write(
+            "Morrowind.ini",
+            "[General]\r\n\
+             GameFile5=NotAPlugin.esp\r\n\
+             [Game Files]\r\n\
+             GameFile0=Morrowind.esm\r\n\
+             GameFile10=Late.esp\r\n\
+             gamefile2 = Bloodmoon.esm\r\n\
+             GameFile1=Tribunal.esm\r\n\
+             ;GameFile3=Commented.esp\r\n\
+             GameFile4=\r\n\
+             GameFiles=Unnumbered.esp\r\n\
+             [Archives]\r\n\
+             Archive 0=Tribunal.bsa\r\n\
+             GameFile6=AfterTheSection.esp\r\n",
+        );
+        let files = read_morrowind_ini(&path).unwrap();
+        assert_eq!(
+            files,
+            ["Morrowind.esm", "Tribunal.esm", "Bloodmoon.esm", "Late.esp"]
+        );
+    }
+
+    #[test]
+    fn orders_by_timestamp_with_masters_first() {
+        let dir = TempDir::new();
+        // Unreadable plugins are masters or not by their extension.
+        for (name, secs) in [
+            ("Late.esm", 400),
+            ("B.esp", 300),
+            ("Inactive.esp", 250),
+            ("A.esp", 200),
+            ("C.esp", 150),
+            ("FalloutNV.esm", 100),
+            ("readme.txt", 50),
+        ] {
+            let path = dir.write(&format!("Data/{}", name), "");
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        }
+        dir.write(
+            "Local/plugins.txt",
+            "FalloutNV.esm\nC.esp\nB.esp\nMissing.esp\nA.esp\nLate.esm\n",
+        );
+        let settings = GameSettings::new(&InstalledGame {
+            game: Game::FalloutNV,
+            source: InstallSource::Override,
+            install_path: dir.path().to_owned(),
+            data_path: dir.path().join("Data"),
+            local_path: Some(dir.path().join("Local")),
+        });
+        assert_eq!(settings.load_order_method, LoadOrderMethod::Timestamp);
+
+        let entries = read_load_order(&settings).unwrap();
+        let entries: Vec<(&str, bool, bool)> = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.active, entry.master))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("FalloutNV.esm", true, true),
+                ("Late.esm", true, true),
+                ("C.esp", true, false),
+                ("A.esp", true, false),
+                ("Inactive.esp", false, false),
+                ("B.esp", true, false),
+                // Listed but not found, so it has no timestamp.
+                ("Missing.esp", true, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn packs_light_plugins_into_fe() {
+        let load_order: Vec<LoadOrderEntry> = [
+            ("Skyrim.esm", true, false),
+            ("A.esl", true, true),
+            ("Inactive.esp", false, false),
+            ("B.esp", true, false),
+            ("InactiveLight.esp", false, true),
+            ("C.esp", true, true),
+        ]
+        .into_iter()
+        .map(|(name, active, light)| LoadOrderEntry {
+            light,
+            ..LoadOrderEntry::new(name, active)
+        })
+        .collect();
+        let runtime = |plugin: &str, object_id: u32| {
+            runtime_form_id(&load_order, &GlobalFormId::new(plugin, object_id))
+        };
+        assert_eq!(runtime("Skyrim.esm", 0x00_0100), Some(0x0000_0100));
+        assert_eq!(runtime("a.esl", 0x00_0801), Some(0xFE00_0801));
+        // Inactive plugins don't take up an index.
+        assert_eq!(runtime("B.esp", 0x00_0900), Some(0x0100_0900));
+        assert_eq!(runtime("C.esp", 0x00_0ABC), Some(0xFE00_1ABC));
+        assert_eq!(runtime("Inactive.esp", 0x00_0900), None);
+        assert_eq!(runtime("Missing.esp", 0x00_0900), None);
+    }
+}
@@ -0,0 +1,152 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+use log::debug;
+
+use crate::registry_install_path;
+use crate::registry_path;
+use crate::steam;
+use crate::Game;
+
+/// Where an installed game was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstallSource {
+    /// Given explicitly with `GameLocator::with_override`.
+    Override,
+    /// The "installed path" the game's launcher wrote to the registry.
+    Registry,
+    Steam,
+    Gog,
+    /// The game a Mod Organizer 2 instance manages.
+    ModOrganizer,
+}
+
+#[derive(Debug, Clone)]
+pub struct InstalledGame {
+    pub game: Game,
+    pub source: InstallSource,
+    pub install_path: PathBuf,
+    /// Directory the game loads plugins from.
+    pub data_path: PathBuf,
+    /// The game's `%LOCALAPPDATA%` folder, holding plugins.txt.
+    pub local_path: Option<PathBuf>,
+}
+
+/// Finds installed games, probing in order: explicit overrides, the
+/// registry, Steam's libraries and finally GOG's registry keys.
+#[derive(Debug, Clone, Default)]
+pub struct GameLocator {
+    overrides: Vec<(Game, PathBuf)>,
+}
+
+#[cfg(windows)]
+fn local_app_data(_game: Game) -> Option<PathBuf> {
+    std::env::var_os("LOCALAPPDATA")
+        .map(PathBuf::from)
+        .or_else(|| std::env::home_dir().map(|home| home.join("AppData").join("Local")))
+}
+
+// Everywhere else the game runs through Proton, which gives every game its
+// own Windows user profile, or through plain Wine.
+#[cfg(not(windows))]
+fn local_app_data(game: Game) -> Option<PathBuf> {
+    match steam::proton_prefix(game) {
+        Some(prefix) => Some(steam::prefix_local_app_data(&prefix)),
+        None => crate::wine::default_prefix()
+            .and_then(|prefix| crate::wine::prefix_local_app_data(&prefix)),
+    }
+}
+
+/// The game's `%LOCALAPPDATA%` folder. The GOG release of Skyrim Special
+/// Edition keeps its own, so it doesn't clash with the Steam release.
+fn local_path(game: Game, source: InstallSource) -> Option<PathBuf> {
+    let folder = game.local_folder_name()?;
+    let folder = match (game, source) {
+        (Game::SkyrimSE, InstallSource::Gog) => format!("{} GOG", folder),
+        _ => String::from(folder),
+    };
+    Some(local_app_data(game)?.join(folder))
+}
+
+fn existing_dir<S>(path: S) -> Option<PathBuf>
+where
+    S: AsRef<Path>,
+{
+    let path = path.as_ref();
+    if path.is_dir() {
+        Some(path.to_owned())
+    } else {
+        None
+    }
+}
+
+impl GameLocator {
+    pub fn new() -> Self {
+        GameLocator { overrides: vec![] }
+    }
+
+    /// Use `path` as the install directory of `game` rather than looking for it.
+    pub fn with_override<P>(mut self, game: Game, path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.overrides.retain(|(g, _)| *g != game);
+        self.overrides.push((game, path.into()));
+        self
+    }
+
+    fn find_install_path(&self, game: Game) -> Option<(InstallSource, PathBuf)> {
+        if let Some((_, path)) = self.overrides.iter().find(|(g, _)| *g == game) {
+            return Some((InstallSource::Override, path.clone()));
+        }
+        for key in game.registry_keys() {
+            if let Some(path) = registry_install_path(key).ok().and_then(existing_dir) {
+                return Some((InstallSource::Registry, path));
+            }
+        }
+        if let Some(prefix) = steam::proton_prefix(game) {
+            for key in game.registry_keys() {
+                let path = crate::wine::prefix_install_path(&prefix, &key);
+                if let Some(path) = path.ok().and_then(existing_dir) {
+                    return Some((InstallSource::Steam, path));
+                }
+            }
+        }
+        if let Some(path) = steam::steam_install_path(game) {
+            return Some((InstallSource::Steam, path));
+        }
+        for id in game.gog_ids() {
+            let key = format!("SOFTWARE\\WOW6432Node\\GOG.com\\Games\\{}", id);
+            if let Some(path) = registry_path(&key, "path").ok().and_then(existing_dir) {
+                return Some((InstallSource::Gog, path));
+            }
+        }
+        None
+    }
+
+    /// Find a single game, if it is installed.
+    pub fn locate(&self, game: Game) -> Option<InstalledGame> {
+        let (source, install_path) = self.find_install_path(game)?;
+        debug!(
+            "Found {} through {:?} at {}",
+            game,
+            source,
+            install_path.display()
+        );
+        Some(InstalledGame {
+            game,
+            source,
+            data_path: install_path.join(game.data_folder_name()),
+            local_path: local_path(game, source),
+            install_path,
+        })
+    }
+
+    /// Every supported game we can find.
+    pub fn installed_games(&self) -> Vec<InstalledGame> {
+        Game::ALL
+            .iter()
+            .filter_map(|game| self.locate(*game))
+            .collect()
+    }
+}
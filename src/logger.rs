@@ -0,0 +1,65 @@
+//! The binary's logger. Messages go to stderr, keeping stdout for the
+//! output of commands, and optionally to a log file as well.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+struct Logger {
+    level: LevelFilter,
+    file: Option<Mutex<File>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Error => eprintln!("[Error] {}", record.args()),
+            Level::Warn => eprintln!("[Warning] {}", record.args()),
+            Level::Info => eprintln!("{}", record.args()),
+            Level::Debug => eprintln!("[Debug] {}: {}", record.target(), record.args()),
+            Level::Trace => eprintln!("[Trace] {}: {}", record.target(), record.args()),
+        }
+        if let Some(ref file) = self.file {
+            let mut file = file.lock().unwrap();
+            // Nowhere left to report a failure to log.
+            let _ = writeln!(
+                file,
+                "{:<5} {}: {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(ref file) = self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+/// Install the logger, logging `level` and everything more severe.
+pub fn init(level: LevelFilter, log_file: Option<&Path>) -> Result<(), String> {
+    let file = match log_file {
+        Some(path) => Some(Mutex::new(File::create(path).map_err(|e| {
+            format!("Unable to create log file `{}`: {}", path.display(), e)
+        })?)),
+        None => None,
+    };
+    // The logger lives as long as the program does.
+    let logger = Box::leak(Box::new(Logger { level, file }));
+    log::set_logger(logger).map_err(|e| e.to_string())?;
+    log::set_max_level(level);
+    Ok(())
+}
@@ -1,141 +1,1512 @@
 #![allow(dead_code)]
 #![allow(non_camel_case_types)]
 extern crate byteorder;
-extern crate winreg;
 
-use std::collections::HashMap;
-use std::env;
-use std::fs::File;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
 use std::path::Path;
+use std::path::PathBuf;
+use std::process;
 
-use std::io::BufRead;
-use std::io::BufReader;
-use winreg::RegKey;
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use log::{debug, error, info, warn, LevelFilter};
 
+use gamebryo_music_merge::config::Config;
+use gamebryo_music_merge::merge::{
+    merge_record, MergeOutcome, MergeStrategy, MergedRecord, MusicVersion, RecordVersions,
+    Strategy, ThreeWay,
+};
+use gamebryo_music_merge::mo2::{Mo2Instance, Mo2Output};
+use gamebryo_music_merge::plugin_writer::{write_plugin, WriteOptions};
+use gamebryo_music_merge::records::{Subrecord, MUSC, MUST};
+use gamebryo_music_merge::report::*;
+use gamebryo_music_merge::vortex::VortexDeployment;
 use gamebryo_music_merge::*;
 
-#[allow(dead_code)]
-const DEFAULT_S_INSTALL_DIR: &'static str =
-    "C:\\Program Files (x86)\\Steam\\steamapps\\common\\skyrim";
-#[allow(dead_code)]
-const DEFAULT_SSE_INSTALL_DIR: &'static str =
-    "C:\\Program Files (x86)\\Steam\\steamapps\\common\\Skyrim Special Edition";
+mod logger;
 
-#[allow(dead_code)]
-const S_REGISTRY_KEY_X32: &'static str = "SOFTWARE\\Bethesda Softworks\\skyrim"; // registry key location for Skyrim on a 32 bit Windows OS.
-#[allow(dead_code)]
-const S_REGISTRY_KEY_X64: &'static str = "SOFTWARE\\WOW6432NODE\\Bethesda Softworks\\skyrim"; // registry key location for Skyrim on a 64 bit Windows OS.
+/// Mod name the patch is staged under when no --output-mod is given.
+const DEFAULT_OUTPUT_MOD: &str = "Music Merge Patch";
 
-const SSE_REGISTRY_KEY: &'static str =
-    "SOFTWARE\\WOW6432NODE\\Bethesda Softworks\\Skyrim Special Edition"; // Skyrim Special Edition registry key location.
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Merge music from Bethesda game plugins into a single patch."
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    /// Game to work on, such as `skyrimse` or `fallout4`. Detected when not given.
+    #[arg(short, long, global = true)]
+    game: Option<Game>,
+    /// Where the game is installed. Requires --game.
+    #[arg(long, global = true)]
+    install_dir: Option<PathBuf>,
+    /// Directory to load plugins from, if not the game's Data directory.
+    #[arg(long, global = true)]
+    data_dir: Option<PathBuf>,
+    /// plugins.txt to read the load order from.
+    #[arg(long, global = true)]
+    plugins_txt: Option<PathBuf>,
+    /// Mod Organizer 2 instance to use. Detected when run from one.
+    #[arg(long, global = true)]
+    mo2: Option<PathBuf>,
+    /// Mod Organizer 2 profile to use, if not the selected one.
+    #[arg(long, global = true)]
+    profile: Option<String>,
+    /// Print more detail. Use twice for even more.
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Only print warnings and errors.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Also write messages to this file.
+    #[arg(long, global = true)]
+    log_file: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Merge the music records of every active plugin into a patch.
+    Merge(MergeArgs),
+    /// List the load order.
+    List,
+    /// Show a plugin's header and music records.
+    Inspect {
+        /// Path to the plugin, or the name of a plugin in the load order.
+        plugin: String,
+    },
+    /// Show music records overridden by more than one plugin side by side,
+    /// and what the winning version loses without a patch.
+    Conflicts,
+    /// Compare the music records of two plugins.
+    Diff {
+        /// Path to the plugin, or the name of a plugin in the load order.
+        old: String,
+        /// Path to the plugin, or the name of a plugin in the load order.
+        new: String,
+    },
+}
+
+#[derive(Args)]
+struct MergeArgs {
+    /// File name of the patch. Further patches, when the records need more
+    /// masters than one plugin can have, are numbered from `_2`.
+    #[arg(short, long, default_value = "music_merge_patch.esp")]
+    output: String,
+    /// Most masters a patch can have before the records are split across
+    /// several patches.
+    #[arg(long, default_value_t = Plugin::MAX_MASTERS, value_parser = clap::value_parser!(u8).range(1..))]
+    max_masters: u8,
+    /// Directory to write the patch to. Defaults to the Data directory,
+    /// or wherever the mod manager in use expects it.
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+    /// With Mod Organizer 2 or Vortex, write the patch into a mod of this
+    /// name. Mod Organizer 2 otherwise uses its overwrite folder.
+    #[arg(long)]
+    output_mod: Option<String>,
+    /// Don't flag the patch as a light plugin.
+    #[arg(long)]
+    no_light: bool,
+    /// Print what would be merged instead of writing the patch.
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+    /// Print a report of the merge in this format.
+    #[arg(long, value_enum)]
+    report: Option<ReportFormat>,
+    /// Write the report to this file instead of printing it.
+    #[arg(long, requires = "report")]
+    report_file: Option<PathBuf>,
+    /// How to merge records: union, three-way, last-wins or prefer:<plugin>.
+    /// Overrides the config file.
+    #[arg(long)]
+    strategy: Option<Strategy>,
+    /// TOML or JSON file with settings for the merge.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ReportFormat {
+    Json,
+}
+
+/// The game and load order every command works on.
+struct Context {
+    settings: GameSettings,
+    mo2: Option<Mo2Instance>,
+}
+
+fn find_settings(cli: &Cli, mo2: &Option<Mo2Instance>) -> Result<GameSettings, String> {
+    if let Some(mo2) = mo2 {
+        return mo2
+            .game_settings()
+            .map_err(|e| format!("Unable to read the Mod Organizer 2 profile: {}", e));
+    }
+    let mut locator = GameLocator::new();
+    if let Some(ref install_dir) = cli.install_dir {
+        match cli.game {
+            Some(game) => locator = locator.with_override(game, install_dir),
+            None => return Err(String::from("--install-dir requires --game.")),
+        }
+    }
+    if let Some(game) = cli.game {
+        return locator
+            .locate(game)
+            .map(|installed| GameSettings::new(&installed))
+            .ok_or_else(|| format!("Unable to find {}.", game));
+    }
+    let installed_games = locator.installed_games();
+    for installed in installed_games.iter() {
+        debug!(
+            "{} installed to:\n\t{}",
+            installed.game,
+            installed.install_path.display()
+        );
+    }
+    // Prefer Special Edition when more than one game is installed.
+    installed_games
+        .iter()
+        .find(|installed| installed.game == Game::SkyrimSE)
+        .or_else(|| installed_games.first())
+        .map(GameSettings::new)
+        .ok_or_else(|| String::from("Unable to find any supported game. Try --game."))
+}
+
+fn context(cli: &Cli) -> Result<Context, String> {
+    let mo2 = match cli.mo2 {
+        Some(ref path) => Some(
+            Mo2Instance::open(path, cli.profile.as_deref())
+                .map_err(|e| format!("Unable to open the Mod Organizer 2 instance: {}", e))?,
+        ),
+        // An explicit install directory means the game isn't run through MO2.
+        None if cli.install_dir.is_none() => {
+            Mo2Instance::detect().filter(|mo2| cli.game.is_none_or(|game| game == mo2.game))
+        }
+        None => None,
+    };
+    if let Some(ref mo2) = mo2 {
+        info!(
+            "Using Mod Organizer 2 instance at:\n\t{}\n\tprofile: {}",
+            mo2.base_path.display(),
+            mo2.profile
+        );
+    }
+    let mut settings = find_settings(cli, &mo2)?;
+    if let Some(ref data_dir) = cli.data_dir {
+        settings.data_location = data_dir.to_string_lossy().into_owned();
+    }
+    if let Some(ref plugins_txt) = cli.plugins_txt {
+        settings.plugins_file = Some(plugins_txt.to_string_lossy().into_owned());
+    }
+    settings
+        .read_load_order()
+        .map_err(|e| format!("Unable to read the load order: {}", e))?;
+    Ok(Context { settings, mo2 })
+}
+
+/// Parse every active plugin in load order, except those `skip` is true
+/// for. Plugins that can't be read are noted in the report.
+fn load_plugins(ctx: &Context, skip: &dyn Fn(&str) -> bool, report: &mut Report) -> Vec<Plugin> {
+    let mut plugins: Vec<Plugin> = vec![];
+    for plugin_entry in ctx.settings.load_order.iter().filter(|e| e.active) {
+        if skip(&plugin_entry.name) {
+            continue;
+        }
+        let plugin_path = ctx.settings.plugin_path(&plugin_entry.name);
+        if !plugin_path.exists() {
+            let warning = format!("Unable to find {}", plugin_path.to_str().unwrap());
+            warn!("{}", warning);
+            report.warnings.push(warning);
+            continue;
+        }
+        match parse(plugin_path.as_path()) {
+            Ok(plugin) => {
+                if !plugin.music.is_empty() {
+                    debug!("{}: found {} MUSC records", plugin.name, plugin.music.len());
+                }
+                plugins.push(plugin);
+            }
+            Err(err) => {
+                let message = format!("{}: {}", plugin_entry.name, err);
+                error!("{}", message);
+                report.errors.push(message);
+            }
+        }
+    }
+    plugins
+}
+
+/// Read a plugin named on the command line, either by path or by its
+/// name in the load order.
+fn find_plugin(cli: &Cli, plugin: &str) -> Result<Plugin, String> {
+    let path = Path::new(plugin);
+    let path = if path.is_file() {
+        path.to_owned()
+    } else {
+        context(cli)?.settings.plugin_path(plugin)
+    };
+    parse(&path).map_err(|e| format!("Unable to read `{}`: {}", path.display(), e))
+}
+
+/// Where the patch goes. MO2 collects files created by tools in its
+/// overwrite folder, while Vortex needs the patch in its staging folder to
+/// keep track of it. Folders are only created when `create` is set.
+fn output_dir(ctx: &Context, args: &MergeArgs, create: bool) -> Result<PathBuf, String> {
+    if let Some(ref output_dir) = args.output_dir {
+        if create {
+            fs::create_dir_all(output_dir)
+                .map_err(|e| format!("Unable to create the output folder: {}", e))?;
+        }
+        return Ok(output_dir.clone());
+    }
+    if let Some(ref mo2) = ctx.mo2 {
+        let output = match args.output_mod {
+            Some(ref name) => Mo2Output::Mod(name.clone()),
+            None => Mo2Output::Overwrite,
+        };
+        if !create {
+            return Ok(mo2.output_dir(&output));
+        }
+        return mo2
+            .output_path(&output)
+            .map_err(|e| format!("Unable to create the output folder: {}", e));
+    }
+    let data_path = ctx.settings.data_path();
+    if let Some(vortex) = VortexDeployment::detect(&data_path) {
+        let mod_name = args.output_mod.as_deref().unwrap_or(DEFAULT_OUTPUT_MOD);
+        info!(
+            "Data directory is managed by Vortex, staging to:\n\t{}",
+            vortex.staging_path.display()
+        );
+        if !create {
+            return Ok(vortex.staging_path.join(mod_name));
+        }
+        return vortex
+            .output_path(mod_name)
+            .map_err(|e| format!("Unable to create the output folder: {}", e));
+    }
+    Ok(data_path)
+}
+
+fn join_form_ids<'a, I>(ids: I) -> String
+where
+    I: Iterator<Item = &'a GlobalFormId>,
+{
+    ids.map(|id| id.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// The fields of a MUSC record other than its tracks, for display.
+fn musc_fields(musc: &MUSC) -> [(&'static str, serde_json::Value); 4] {
+    [
+        ("flags", musc.flags.into()),
+        ("priority", musc.priority.into()),
+        ("ducking", musc.ducking.into()),
+        ("fade_duration", musc.fade_duration.into()),
+    ]
+}
+
+fn join_changes(changes: &[FieldChange]) -> String {
+    changes
+        .iter()
+        .map(|change| format!("{} {}", change.plugin, change.value))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Print what a merge would do: who contributed which tracks to each
+/// record, what the patch changes from the winning override, and which
+/// masters the patch needs.
+fn print_plan(patch: &Patch, contributions: &BTreeMap<GlobalFormId, RecordVersions>) {
+    println!("Merge plan for {}:", patch.name);
+    for (form_id, record) in patch.records.iter() {
+        let versions = &contributions[form_id];
+        let winner = versions.winner();
+        println!("{} [{}]", record.musc.editor_id, form_id);
+        for version in versions.versions.iter() {
+            println!(
+                "  {}: {}",
+                version.plugin,
+                join_form_ids(version.track_ids.iter())
+            );
+        }
+        let added: Vec<&GlobalFormId> = record
+            .track_ids
+            .iter()
+            .filter(|id| !winner.track_ids.contains(id))
+            .collect();
+        let removed: Vec<&GlobalFormId> = winner
+            .track_ids
+            .iter()
+            .filter(|id| !record.track_ids.contains(id))
+            .collect();
+        let changed: Vec<String> = musc_fields(&winner.musc)
+            .iter()
+            .zip(musc_fields(&record.musc).iter())
+            .filter(|(old, new)| old.1 != new.1)
+            .map(|(old, new)| format!("{} {} -> {}", old.0, old.1, new.1))
+            .collect();
+        if added.is_empty() && removed.is_empty() && changed.is_empty() {
+            println!("  Nothing changed from {}", winner.plugin);
+        }
+        if !added.is_empty() {
+            println!(
+                "  Added to {}: {}",
+                winner.plugin,
+                join_form_ids(added.into_iter())
+            );
+        }
+        if !removed.is_empty() {
+            println!(
+                "  Removed from {}: {}",
+                winner.plugin,
+                join_form_ids(removed.into_iter())
+            );
+        }
+        if !changed.is_empty() {
+            println!("  Changed from {}: {}", winner.plugin, changed.join(", "));
+        }
+        for conflict in record.conflicts.iter() {
+            println!(
+                "  Conflicting {}: {}",
+                conflict.field,
+                join_changes(&conflict.changes)
+            );
+        }
+    }
+    println!("Masters:");
+    for master in patch.masters.iter() {
+        println!("  {}", master);
+    }
+}
+
+/// File name of patch `index`, counting from 0. The first patch is named
+/// `output`, and the rest are numbered from `_2`.
+fn patch_name(output: &str, index: usize) -> String {
+    if index == 0 {
+        return String::from(output);
+    }
+    match output.rsplit_once('.') {
+        Some((stem, ext)) => format!("{}_{}.{}", stem, index + 1, ext),
+        None => format!("{}_{}", output, index + 1),
+    }
+}
+
+/// True if `name` is one of the patches named after `output`.
+fn is_patch(output: &str, name: &str) -> bool {
+    if name.eq_ignore_ascii_case(output) {
+        return true;
+    }
+    let (stem, ext) = output.rsplit_once('.').unwrap_or((output, ""));
+    let name = name.to_lowercase();
+    let number = match name.strip_prefix(&format!("{}_", stem.to_lowercase())) {
+        Some(rest) if ext.is_empty() => rest,
+        Some(rest) => match rest.strip_suffix(&format!(".{}", ext.to_lowercase())) {
+            Some(number) => number,
+            None => return false,
+        },
+        None => return false,
+    };
+    number.parse::<usize>().is_ok_and(|n| n >= 2)
+}
 
-fn get_sse_install() -> String {
-    let hklm = RegKey::predef(winreg::enums::HKEY_LOCAL_MACHINE);
-    let sse_key = hklm
-        .open_subkey_with_flags(SSE_REGISTRY_KEY, winreg::enums::KEY_READ)
-        .unwrap();
-    sse_key
-        .get_value("installed path")
-        .expect("Unable to retrieve Skyrim Special Edition install location.")
+/// One of the patch plugins, with the records it holds.
+struct Patch {
+    name: String,
+    records: BTreeMap<GlobalFormId, MergedRecord>,
+    /// The plugins the records' FormIDs refer to, in load order.
+    masters: Vec<String>,
 }
 
-fn get_sse_load_order() -> Vec<String> {
-    let mut path_buf = env::home_dir().expect("Unable to retrieve home directory.");
-    path_buf.push("AppData");
-    path_buf.push("Local");
-    path_buf.push("Skyrim Special Edition");
-    path_buf.push("plugins");
-    path_buf.set_extension("txt");
-    let path = path_buf.as_path();
-    let plugins_file = File::open(path).expect("Unable to read plugins.txt.");
-    let buf_reader = BufReader::new(plugins_file);
-    let mut entries: Vec<String> = vec![
-        String::from("Skyrim.esm"),
-        String::from("Update.esm"),
-        String::from("Dawnguard.esm"),
-        String::from("Hearthfires.esm"),
-        String::from("Dragonborn.esm"),
-    ];
-    for line in buf_reader.lines() {
-        let s = line.unwrap();
-        if s.starts_with('*') {
-            entries.push(String::from(&s[1..]));
+/// The plugins a record's FormIDs refer to: the plugin defining it and the
+/// plugins defining its tracks.
+fn record_masters(form_id: &GlobalFormId, record: &MergedRecord) -> Vec<String> {
+    let mut masters: Vec<String> = vec![];
+    for id in std::iter::once(form_id).chain(record.track_ids.iter()) {
+        if !masters.iter().any(|m| m.eq_ignore_ascii_case(&id.plugin)) {
+            masters.push(id.plugin.clone());
         }
     }
-    entries
+    masters
 }
 
-fn handle_plugin(p: &Plugin) -> HashMap<String, Vec<u32>> {
-    //println!("Analyzing {}", p.path().to_str().unwrap());
-    let mut map: HashMap<String, Vec<u32>> = HashMap::new();
+/// Split the merged records, in FormID order, across as few patches as
+/// keeps each within `max_masters` masters. Every record is in exactly one
+/// patch, so the patches can load in any order.
+fn split_patches(
+    load_order: &[LoadOrderEntry],
+    output: &str,
+    merged: BTreeMap<GlobalFormId, MergedRecord>,
+    max_masters: usize,
+) -> Result<Vec<Patch>, String> {
+    let mut patches: Vec<Patch> = vec![];
+    let mut patch = Patch {
+        name: patch_name(output, 0),
+        records: BTreeMap::new(),
+        masters: vec![],
+    };
+    for (form_id, record) in merged {
+        let needed = record_masters(&form_id, &record);
+        if needed.len() > max_masters {
+            return Err(format!(
+                "{} [{}] needs {} masters, more than a patch can have ({})",
+                record.musc.editor_id,
+                form_id,
+                needed.len(),
+                max_masters
+            ));
+        }
+        let mut masters = patch.masters.clone();
+        for master in needed {
+            if !masters.iter().any(|m| m.eq_ignore_ascii_case(&master)) {
+                masters.push(master);
+            }
+        }
+        if masters.len() > max_masters {
+            let name = patch_name(output, patches.len() + 1);
+            patches.push(patch);
+            patch = Patch {
+                name,
+                records: BTreeMap::new(),
+                masters: record_masters(&form_id, &record),
+            };
+        } else {
+            patch.masters = masters;
+        }
+        patch.records.insert(form_id, record);
+    }
+    patches.push(patch);
+    for patch in patches.iter_mut() {
+        // Anything missing from the load order goes last.
+        patch.masters.sort_by_key(|master| {
+            load_order
+                .iter()
+                .position(|entry| entry.name.eq_ignore_ascii_case(master))
+                .unwrap_or(usize::MAX)
+        });
+    }
+    Ok(patches)
+}
 
-    for musc in p.music.iter() {
-        map.insert(musc.editor_id.clone(), musc.track_ids.clone());
+/// Merge every record with the config's strategy and rules, returning the
+/// records the patch needs and the names of the rules applied to each.
+fn merge_records(
+    contributions: &BTreeMap<GlobalFormId, RecordVersions>,
+    config: &Config,
+    report: &mut Report,
+) -> (
+    BTreeMap<GlobalFormId, MergedRecord>,
+    BTreeMap<GlobalFormId, Vec<String>>,
+) {
+    let mut merged: BTreeMap<GlobalFormId, MergedRecord> = BTreeMap::new();
+    let mut rules: BTreeMap<GlobalFormId, Vec<String>> = BTreeMap::new();
+    for (form_id, versions) in contributions.iter() {
+        let editor_id = &versions.winner().musc.editor_id;
+        let record = match merge_record(versions, config) {
+            MergeOutcome::Merged(record, rule_names) => {
+                rules.insert(form_id.clone(), rule_names);
+                record
+            }
+            MergeOutcome::Skipped(rule) => {
+                info!(
+                    "Leaving out {} [{}], skipped by {}",
+                    editor_id, form_id, rule
+                );
+                report.skipped.push(SkippedMusic {
+                    form_id: form_id.clone(),
+                    editor_id: editor_id.clone(),
+                    rule,
+                });
+                continue;
+            }
+        };
+        // The game already ends up with this version, so patching it would
+        // only add an ITM of our own, and masters it doesn't need.
+        if record.is_identical(versions.winner()) {
+            debug!("Nothing to patch in {} [{}]", editor_id, form_id);
+            continue;
+        }
+        for conflict in record.conflicts.iter() {
+            warn!(
+                "{} [{}]: {} changed by several plugins: {}",
+                editor_id,
+                form_id,
+                conflict.field,
+                join_changes(&conflict.changes)
+            );
+        }
+        merged.insert(form_id.clone(), record);
     }
+    (merged, rules)
+}
 
-    //println!("\tMUSIC: {:?}", p.music);
-    if p.music.len() > 0 {
-        println!("\tFound {} MUSC records", p.music.len());
+fn cmd_merge(ctx: &Context, args: &MergeArgs, report: &mut Report) -> Result<(), String> {
+    if !ctx.settings.game.supports_music_merge() {
+        return Err(format!(
+            "Merging music isn't supported for {}, only for Skyrim and Fallout 4",
+            ctx.settings.game
+        ));
     }
+    let output_name = args.output.as_str();
+    let mut config = match args.config {
+        Some(ref path) => {
+            Config::read(path).map_err(|e| format!("Unable to read `{}`: {}", path.display(), e))?
+        }
+        None => Config::default(),
+    };
+    if let Some(ref strategy) = args.strategy {
+        config.strategy = strategy.clone();
+    }
+    info!(
+        "Merging music for {} with the {} strategy",
+        ctx.settings.game, config.strategy
+    );
+    report.strategy = Some(config.strategy.to_string());
+    report.load_order = ctx
+        .settings
+        .load_order
+        .iter()
+        .map(ReportPlugin::from)
+        .collect();
 
-    map
+    // Every plugin's version of each record, in load order. Overrides share
+    // the FormID of the record they override, while editor IDs can be renamed
+    // or reused, so they are only for display. Ordered maps keep the merge,
+    // and so the patch, the same from run to run.
+    let mut versions: BTreeMap<GlobalFormId, Vec<MusicVersion>> = BTreeMap::new();
+
+    let skip = |name: &str| is_patch(output_name, name);
+    for plugin in load_plugins(ctx, &skip, report) {
+        if !plugin.music.is_empty() {
+            let mut plugin_music = PluginMusic::from(&plugin);
+            if config.is_excluded(&plugin.name) {
+                info!("Leaving out {}, excluded by the config", plugin.name);
+                plugin_music.excluded = true;
+            }
+            report.plugins.push(plugin_music);
+            // Excluded plugins are kept to know which version the game uses.
+            for musc in plugin.music.iter() {
+                let version = MusicVersion::new(&plugin, musc);
+                versions
+                    .entry(version.form_id.clone())
+                    .or_default()
+                    .push(version);
+            }
+        }
+    }
+    let contributions: BTreeMap<GlobalFormId, RecordVersions> = versions
+        .into_iter()
+        .filter(|(_, versions)| {
+            versions
+                .iter()
+                .any(|version| !config.is_excluded(&version.plugin))
+        })
+        .map(|(form_id, versions)| {
+            let record = RecordVersions::excluding(versions, &config.exclude_plugins);
+            (form_id, record)
+        })
+        .collect();
+    for (form_id, versions) in contributions.iter() {
+        for version in versions.identical.iter() {
+            info!(
+                "Ignoring {}'s override of {} [{}], identical to {}",
+                version.plugin,
+                version.musc.editor_id,
+                form_id,
+                versions.base().plugin
+            );
+            report.identical.push(IdenticalOverride {
+                form_id: form_id.clone(),
+                editor_id: version.musc.editor_id.clone(),
+                plugin: version.plugin.clone(),
+            });
+        }
+    }
+    let (merged, rules) = merge_records(&contributions, &config, report);
+    let output_path = output_dir(ctx, args, !args.dry_run)?;
+    for (form_id, record) in merged.iter() {
+        let versions = &contributions[form_id];
+        let base = versions.base();
+        report.merged.push(MergedMusic {
+            form_id: form_id.clone(),
+            editor_id: record.musc.editor_id.clone(),
+            winner: versions.winner().plugin.clone(),
+            base: base.plugin.clone(),
+            flags: record.musc.flags,
+            priority: record.musc.priority,
+            ducking: record.musc.ducking,
+            fade_duration: record.musc.fade_duration,
+            tracks: record
+                .track_ids
+                .iter()
+                .map(|track_id| MergedTrack {
+                    form_id: track_id.clone(),
+                    plugins: versions
+                        .versions
+                        .iter()
+                        .filter(|version| version.track_ids.contains(track_id))
+                        .map(|version| version.plugin.clone())
+                        .collect(),
+                })
+                .collect(),
+            removed: base
+                .track_ids
+                .iter()
+                .filter(|track_id| !record.track_ids.contains(track_id))
+                .map(|track_id| MergedTrack {
+                    form_id: track_id.clone(),
+                    plugins: versions
+                        .versions
+                        .iter()
+                        .filter(|version| !version.track_ids.contains(track_id))
+                        .map(|version| version.plugin.clone())
+                        .collect(),
+                })
+                .collect(),
+            conflicts: record.conflicts.clone(),
+            rules: rules[form_id].clone(),
+        });
+    }
+    let patches = split_patches(
+        &ctx.settings.load_order,
+        output_name,
+        merged,
+        args.max_masters as usize,
+    )?;
+    if patches.len() > 1 {
+        info!(
+            "The records need more than {} masters, splitting them across {} patches",
+            args.max_masters,
+            patches.len()
+        );
+    }
+    let mut output_plugins: Vec<Plugin> = vec![];
+    for patch in patches.iter() {
+        let mut output_plugin = Plugin::new(&output_path.join(Path::new(&patch.name)));
+        output_plugin.author = String::from("ESMusicMerger");
+        output_plugin.masters = patch.masters.clone();
+        output_plugin.description = format!("Collection of music from {:?}", output_plugin.masters);
+        output_plugin.version = ctx.settings.game.plugin_version();
+
+        debug!("{} masters: {:?}", patch.name, patch.masters);
+
+        for (form_id, record) in patch.records.iter() {
+            let mut musc = record.musc.clone();
+            musc.form_id = output_plugin
+                .local_form_id(form_id)
+                .ok_or("Merged record's plugin is missing from the output masters.")?;
+            musc.track_ids = record
+                .track_ids
+                .iter()
+                .filter_map(|id| output_plugin.local_form_id(id))
+                .collect();
+            output_plugin.music.push(musc);
+        }
+        output_plugin.music.sort_by_key(|musc| musc.form_id);
+        if !output_plugin.music.is_empty() {
+            // Every record, plus the MUSC group.
+            output_plugin.num_records = output_plugin.music.len() as i32 + 1;
+        }
+        report.outputs.push(ReportOutput {
+            path: output_plugin.path().to_string_lossy().into_owned(),
+            masters: patch.masters.clone(),
+            written: false,
+        });
+        output_plugins.push(output_plugin);
+    }
+    report.output = report.outputs.first().cloned();
+    // A patch left over from an earlier merge would override these ones.
+    let mut stale: Vec<PathBuf> = fs::read_dir(&output_path)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            is_patch(output_name, &name)
+                && !patches
+                    .iter()
+                    .any(|patch| patch.name.eq_ignore_ascii_case(&name))
+        })
+        .map(|entry| entry.path())
+        .collect();
+    stale.sort();
+    for path in stale {
+        warn!(
+            "{} is from an earlier merge and no longer needed, remove or disable it",
+            path.display()
+        );
+    }
+
+    if args.dry_run {
+        // Leave stdout to the report when it's printed there.
+        if args.report.is_none() || args.report_file.is_some() {
+            for patch in patches.iter() {
+                print_plan(patch, &contributions);
+            }
+        }
+        for output_plugin in output_plugins.iter() {
+            info!("Dry run, not writing {}", output_plugin.path().display());
+        }
+        return Ok(());
+    }
+
+    let mut options = WriteOptions::for_game(ctx.settings.game);
+    if args.no_light {
+        options.light = false;
+    }
+    for (index, output_plugin) in output_plugins.iter().enumerate() {
+        // Write to memory first so a failed write doesn't leave a broken plugin behind.
+        let mut buf: Vec<u8> = vec![];
+        write_plugin(&mut buf, output_plugin, &options).map_err(|e| e.to_string())?;
+        fs::write(output_plugin.path(), buf)
+            .map_err(|e| format!("Unable to write output plugin: {}", e))?;
+        report.outputs[index].written = true;
+        report.output = report.outputs.first().cloned();
+        info!("Wrote {}", output_plugin.path().display());
+    }
+    Ok(())
+}
+
+fn write_report(report: &Report, args: &MergeArgs) -> Result<(), String> {
+    let text = match args.report {
+        Some(ReportFormat::Json) => {
+            serde_json::to_string_pretty(report).map_err(|e| e.to_string())?
+        }
+        None => return Ok(()),
+    };
+    match args.report_file {
+        Some(ref path) => {
+            fs::write(path, text + "\n").map_err(|e| format!("Unable to write the report: {}", e))
+        }
+        None => {
+            println!("{}", text);
+            Ok(())
+        }
+    }
+}
+
+fn cmd_list(ctx: &Context) {
+    let load_order = &ctx.settings.load_order;
+    println!("{} load order:", ctx.settings.game);
+    for entry in load_order.iter() {
+        // The index the game gives the plugin, written the way xEdit does.
+        let index = match runtime_form_id(load_order, &GlobalFormId::new(entry.name.as_str(), 0)) {
+            Some(id) if entry.light => format!("FE:{:03X}", (id >> 12) & 0xFFF),
+            Some(id) => format!("{:02X}", id >> 24),
+            None => String::new(),
+        };
+        println!(
+            "{:>6} {}{} {}",
+            index,
+            if entry.master { "M" } else { " " },
+            if entry.light { "L" } else { " " },
+            entry.name
+        );
+    }
+}
+
+fn print_musc(plugin: &Plugin, musc: &MUSC) {
+    println!(
+        "  {} [{}]",
+        musc.editor_id,
+        plugin.resolve_form_id(musc.form_id)
+    );
+    println!(
+        "    flags: {:#010X}, priority: {}, ducking: {}, fade duration: {}",
+        musc.flags, musc.priority, musc.ducking, musc.fade_duration
+    );
+    for track_id in musc.track_ids.iter() {
+        println!("    track: {}", plugin.resolve_form_id(*track_id));
+    }
+}
+
+fn cmd_inspect(plugin: &Plugin) {
+    let mut flags: Vec<&str> = vec![];
+    if plugin.is_master() {
+        flags.push("master");
+    }
+    if plugin.is_light() {
+        flags.push("light");
+    }
+    if plugin.is_localized() {
+        flags.push("localized");
+    }
+    if flags.is_empty() {
+        flags.push("none");
+    }
+    println!("{}", plugin.path().display());
+    println!("  Version: {}", plugin.version);
+    println!("  Flags: {:#010X} ({})", plugin.flags, flags.join(", "));
+    println!("  Author: {}", plugin.author);
+    println!("  Description: {}", plugin.description);
+    println!("  Records: {}", plugin.num_records);
+    println!("  Next object ID: {:#08X}", plugin.next_object_id);
+    println!("Masters:");
+    for (index, master) in plugin.masters.iter().enumerate() {
+        println!("  [{:02X}] {}", index, master);
+    }
+    println!("MUSC records ({}):", plugin.music.len());
+    for musc in plugin.music.iter() {
+        print_musc(plugin, musc);
+    }
+}
+
+/// A row of a conflict table: a field, and its value in each version.
+/// Versions without the field have an empty cell.
+type ConflictRow = (String, Vec<String>);
+
+/// Longest value shown in a conflict table.
+const CELL_WIDTH: usize = 24;
+
+fn cell(value: String) -> String {
+    if value.chars().count() <= CELL_WIDTH {
+        return value;
+    }
+    let mut value: String = value.chars().take(CELL_WIDTH - 3).collect();
+    value.push_str("...");
+    value
+}
+
+/// Print the versions of a record side by side, one column per plugin.
+/// Rows where the versions differ are marked with `*`.
+fn print_conflict_table(plugins: &[String], rows: &[ConflictRow]) {
+    let label_width = rows.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+    let widths: Vec<usize> = plugins
+        .iter()
+        .enumerate()
+        .map(|(index, plugin)| {
+            rows.iter()
+                .map(|(_, cells)| cells[index].len())
+                .fold(plugin.len(), usize::max)
+        })
+        .collect();
+    let print_row = |mark: &str, label: &str, cells: &[String]| {
+        let mut line = format!("  {} {:<width$}", mark, label, width = label_width);
+        for (cell, width) in cells.iter().zip(widths.iter()) {
+            line.push_str(&format!("  {:<width$}", cell, width = width));
+        }
+        println!("{}", line.trim_end());
+    };
+    print_row(" ", "", plugins);
+    for (label, cells) in rows.iter() {
+        let mark = if cells.iter().all(|cell| *cell == cells[0]) {
+            " "
+        } else {
+            "*"
+        };
+        print_row(mark, label, cells);
+    }
+}
+
+/// Print a record's conflict table and what the winner loses, compared to
+/// the merge described by `compared_to`.
+fn print_conflict(
+    title: &str,
+    plugins: &[String],
+    rows: &[ConflictRow],
+    lost: &[String],
+    compared_to: &str,
+) {
+    println!("{}", title);
+    print_conflict_table(plugins, rows);
+    println!("  Winner: {}", plugins.last().unwrap());
+    if lost.is_empty() {
+        println!("  Nothing lost without a patch");
+    } else {
+        println!("  Lost without a patch, compared to {}:", compared_to);
+        for change in lost.iter() {
+            println!("    {}", change);
+        }
+    }
+}
+
+/// Compare the versions of a MUSC record, and find what the winner loses
+/// compared to a three-way merge.
+fn musc_conflict(record: &RecordVersions) -> (Vec<ConflictRow>, Vec<String>) {
+    let mut rows: Vec<ConflictRow> = vec![];
+    for (index, (field, _)) in musc_fields(&record.base().musc).iter().enumerate() {
+        let cells = record
+            .all
+            .iter()
+            .map(|version| cell(musc_fields(&version.musc)[index].1.to_string()))
+            .collect();
+        rows.push((String::from(*field), cells));
+    }
+    let mut tracks: Vec<&GlobalFormId> = vec![];
+    for track in record.all.iter().flat_map(|v| v.track_ids.iter()) {
+        if !tracks.contains(&track) {
+            tracks.push(track);
+        }
+    }
+    for track in tracks {
+        let cells = record
+            .all
+            .iter()
+            .map(|version| {
+                let listed = version.track_ids.contains(track);
+                String::from(if listed { "x" } else { "" })
+            })
+            .collect();
+        rows.push((track.to_string(), cells));
+    }
+
+    let merged = ThreeWay.merge(record);
+    let winner = record.winner();
+    let overrides = record.overrides();
+    let plugins = |listing: bool, track: &GlobalFormId| -> String {
+        overrides
+            .iter()
+            .filter(|version| version.track_ids.contains(track) == listing)
+            .map(|version| version.plugin.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ")
+    };
+    let mut lost = vec![];
+    for track in merged.track_ids.iter() {
+        if !winner.track_ids.contains(track) {
+            lost.push(format!("track {} added by {}", track, plugins(true, track)));
+        }
+    }
+    for track in winner.track_ids.iter() {
+        if !merged.track_ids.contains(track) {
+            lost.push(format!(
+                "removal of track {} by {}",
+                track,
+                plugins(false, track)
+            ));
+        }
+    }
+    for ((field, old), (_, new)) in musc_fields(&winner.musc)
+        .iter()
+        .zip(musc_fields(&merged.musc).iter())
+    {
+        if old != new {
+            // The merge takes the last override to change the field.
+            let plugin = overrides
+                .iter()
+                .rev()
+                .find(|version| {
+                    musc_fields(&version.musc)
+                        .iter()
+                        .any(|f| f.0 == *field && f.1 == *new)
+                })
+                .map(|version| version.plugin.as_str())
+                .unwrap_or_default();
+            lost.push(format!("{} {} from {}", field, new, plugin));
+        }
+    }
+    (rows, lost)
+}
+
+/// A MUST subrecord, for display. FormIDs are resolved against `plugin`.
+fn must_value(plugin: &Plugin, subrecord: &Subrecord) -> String {
+    match subrecord.kind.as_str() {
+        "EDID" | "ANAM" | "BNAM" => String::from_utf8_lossy(&subrecord.data)
+            .trim_end_matches('\0')
+            .to_string(),
+        "SNAM" => {
+            let ids: Vec<GlobalFormId> = subrecord
+                .data
+                .chunks_exact(4)
+                .map(|id| plugin.resolve_form_id(u32::from_le_bytes([id[0], id[1], id[2], id[3]])))
+                .collect();
+            join_form_ids(ids.iter())
+        }
+        _ => subrecord
+            .data
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<String>>()
+            .join(" "),
+    }
+}
+
+/// True if two versions of a MUST record have the same subrecords, with
+/// FormIDs resolved against each version's plugin.
+fn must_is_identical(a: (&Plugin, &MUST), b: (&Plugin, &MUST)) -> bool {
+    a.1.subrecords.len() == b.1.subrecords.len()
+        && a.1
+            .subrecords
+            .iter()
+            .zip(b.1.subrecords.iter())
+            .all(|(x, y)| x.kind == y.kind && must_value(a.0, x) == must_value(b.0, y))
+}
+
+/// Compare the versions of a MUST record field by field. The winner loses
+/// the last change an override made to a field, if it doesn't make the
+/// same change itself.
+fn must_conflict(versions: &[(&Plugin, &MUST)], base: usize) -> (Vec<ConflictRow>, Vec<String>) {
+    let mut kinds: Vec<&str> = vec![];
+    for (_, must) in versions.iter() {
+        for subrecord in must.subrecords.iter() {
+            if !kinds.contains(&subrecord.kind.as_str()) {
+                kinds.push(&subrecord.kind);
+            }
+        }
+    }
+    // Subrecords like CTDA can repeat, so join their values.
+    let value = |plugin: &Plugin, must: &MUST, kind: &str| -> String {
+        must.subrecords
+            .iter()
+            .filter(|subrecord| subrecord.kind == kind)
+            .map(|subrecord| must_value(plugin, subrecord))
+            .collect::<Vec<String>>()
+            .join(" | ")
+    };
+    let mut rows: Vec<ConflictRow> = vec![];
+    let mut lost = vec![];
+    let winner = versions.len() - 1;
+    for kind in kinds {
+        let values: Vec<String> = versions
+            .iter()
+            .map(|(plugin, must)| value(plugin, must, kind))
+            .collect();
+        let last_change = (0..versions.len())
+            .rev()
+            .find(|index| *index != base && values[*index] != values[base]);
+        if let Some(index) = last_change {
+            if values[index] != values[winner] {
+                lost.push(format!(
+                    "{} {} from {}",
+                    kind,
+                    cell(values[index].clone()),
+                    versions[index].0.name
+                ));
+            }
+        }
+        rows.push((String::from(kind), values.into_iter().map(cell).collect()));
+    }
+    (rows, lost)
+}
+
+/// Note the overrides identical to the base version (ITMs), which are left
+/// out of the merge.
+fn print_identical(base: &str, identical: &[&str], itm_wins: bool) {
+    if itm_wins {
+        println!(
+            "  {} is identical to {}, undoing the overrides before it",
+            identical.last().unwrap(),
+            base
+        );
+    } else if !identical.is_empty() {
+        println!(
+            "  Ignored as identical to {}: {}",
+            base,
+            identical.join(", ")
+        );
+    }
+}
+
+/// Show every music record overridden by more than one plugin, with each
+/// plugin's version side by side, the winner, and what the winner loses.
+fn cmd_conflicts(ctx: &Context) {
+    let plugins = load_plugins(ctx, &|_| false, &mut Report::new(Some(ctx.settings.game)));
+    let mut music: HashMap<GlobalFormId, Vec<MusicVersion>> = HashMap::new();
+    let mut tracks: HashMap<GlobalFormId, Vec<(&Plugin, &MUST)>> = HashMap::new();
+    let mut music_order: Vec<GlobalFormId> = vec![];
+    let mut track_order: Vec<GlobalFormId> = vec![];
+    for plugin in plugins.iter() {
+        for musc in plugin.music.iter() {
+            let version = MusicVersion::new(plugin, musc);
+            if !music.contains_key(&version.form_id) {
+                music_order.push(version.form_id.clone());
+            }
+            music
+                .entry(version.form_id.clone())
+                .or_default()
+                .push(version);
+        }
+        for must in plugin.tracks.iter() {
+            let form_id = plugin.resolve_form_id(must.form_id);
+            if !tracks.contains_key(&form_id) {
+                track_order.push(form_id.clone());
+            }
+            tracks.entry(form_id).or_default().push((plugin, must));
+        }
+    }
+    for form_id in music_order {
+        let record = RecordVersions::new(music.remove(&form_id).unwrap());
+        // An ITM winning over a single override still undoes it.
+        if record.overrides().len() < 2 && !record.itm_wins() {
+            continue;
+        }
+        let (rows, lost) = musc_conflict(&record);
+        let plugins: Vec<String> = record.all.iter().map(|v| v.plugin.clone()).collect();
+        let title = format!("{} [{}] MUSC", record.winner().musc.editor_id, form_id);
+        print_conflict(&title, &plugins, &rows, &lost, "a three-way merge");
+        let identical: Vec<&str> = record
+            .identical
+            .iter()
+            .map(|version| version.plugin.as_str())
+            .collect();
+        print_identical(&record.base().plugin, &identical, record.itm_wins());
+    }
+    for form_id in track_order {
+        let versions = &tracks[&form_id];
+        let base = versions
+            .iter()
+            .position(|(plugin, _)| plugin.name.eq_ignore_ascii_case(&form_id.plugin));
+        // Like MUSC records, ITMs only count when the defining plugin is loaded.
+        let identical: Vec<&str> = match base {
+            Some(base) => versions
+                .iter()
+                .enumerate()
+                .filter(|(index, version)| {
+                    *index != base && must_is_identical(**version, versions[base])
+                })
+                .map(|(_, (plugin, _))| plugin.name.as_str())
+                .collect(),
+            None => vec![],
+        };
+        let overrides = versions.len() - base.map_or(0, |_| 1) - identical.len();
+        let winner = &versions.last().unwrap().0.name;
+        let itm_wins = overrides > 0 && identical.contains(&winner.as_str());
+        if overrides < 2 && !itm_wins {
+            continue;
+        }
+        let (rows, lost) = must_conflict(versions, base.unwrap_or(0));
+        let plugins: Vec<String> = versions.iter().map(|(p, _)| p.name.clone()).collect();
+        let title = format!(
+            "{} [{}] MUST",
+            versions.last().unwrap().1.editor_id,
+            form_id
+        );
+        // Patches don't merge MUST records, so there's no merge to compare against.
+        print_conflict(
+            &title,
+            &plugins,
+            &rows,
+            &lost,
+            "taking each field from the last override to change it",
+        );
+        print_identical(&plugins[base.unwrap_or(0)], &identical, itm_wins);
+    }
+}
+
+fn cmd_diff(old: &Plugin, new: &Plugin) {
+    let resolve = |plugin: &Plugin, ids: &[u32]| -> Vec<GlobalFormId> {
+        ids.iter().map(|id| plugin.resolve_form_id(*id)).collect()
+    };
+    for old_musc in old.music.iter() {
+        let form_id = old.resolve_form_id(old_musc.form_id);
+        if !new
+            .music
+            .iter()
+            .any(|musc| new.resolve_form_id(musc.form_id) == form_id)
+        {
+            println!("- {} [{}]", old_musc.editor_id, form_id);
+        }
+    }
+    for new_musc in new.music.iter() {
+        let form_id = new.resolve_form_id(new_musc.form_id);
+        let old_musc = match old
+            .music
+            .iter()
+            .find(|musc| old.resolve_form_id(musc.form_id) == form_id)
+        {
+            Some(old_musc) => old_musc,
+            None => {
+                println!("+ {} [{}]", new_musc.editor_id, form_id);
+                continue;
+            }
+        };
+        let mut changes: Vec<String> = vec![];
+        if old_musc.editor_id != new_musc.editor_id {
+            changes.push(format!(
+                "editor ID: {} -> {}",
+                old_musc.editor_id, new_musc.editor_id
+            ));
+        }
+        if old_musc.flags != new_musc.flags {
+            changes.push(format!(
+                "flags: {:#010X} -> {:#010X}",
+                old_musc.flags, new_musc.flags
+            ));
+        }
+        if old_musc.priority != new_musc.priority {
+            changes.push(format!(
+                "priority: {} -> {}",
+                old_musc.priority, new_musc.priority
+            ));
+        }
+        if old_musc.ducking != new_musc.ducking {
+            changes.push(format!(
+                "ducking: {} -> {}",
+                old_musc.ducking, new_musc.ducking
+            ));
+        }
+        if old_musc.fade_duration != new_musc.fade_duration {
+            changes.push(format!(
+                "fade duration: {} -> {}",
+                old_musc.fade_duration, new_musc.fade_duration
+            ));
+        }
+        let old_tracks = resolve(old, &old_musc.track_ids);
+        let new_tracks = resolve(new, &new_musc.track_ids);
+        for track in old_tracks.iter().filter(|t| !new_tracks.contains(t)) {
+            changes.push(format!("- track {}", track));
+        }
+        for track in new_tracks.iter().filter(|t| !old_tracks.contains(t)) {
+            changes.push(format!("+ track {}", track));
+        }
+        if !changes.is_empty() {
+            println!("~ {} [{}]", new_musc.editor_id, form_id);
+            for change in changes {
+                println!("    {}", change);
+            }
+        }
+    }
+}
+
+fn run(cli: &Cli) -> Result<(), String> {
+    match cli.command {
+        Command::Merge(ref args) => {
+            // Failures still go in the report, so the report is written
+            // whatever happens.
+            let mut report = Report::new(cli.game);
+            let result = context(cli).and_then(|ctx| {
+                report.game = Some(String::from(ctx.settings.game.id()));
+                cmd_merge(&ctx, args, &mut report)
+            });
+            if let Err(ref err) = result {
+                report.errors.push(err.clone());
+            }
+            write_report(&report, args)?;
+            result?
+        }
+        Command::List => cmd_list(&context(cli)?),
+        Command::Inspect { ref plugin } => cmd_inspect(&find_plugin(cli, plugin)?),
+        Command::Conflicts => cmd_conflicts(&context(cli)?),
+        Command::Diff { ref old, ref new } => {
+            cmd_diff(&find_plugin(cli, old)?, &find_plugin(cli, new)?)
+        }
+    }
+    Ok(())
 }
 
 fn main() {
-    let output_name = "music_merge_patch.esp";
-    let mut output_masters: Vec<String> = vec![];
-
-    let install_dir = get_sse_install();
-    println!("Skyrim Special Edition installed to:\n\t{}", install_dir);
-    let load_order = get_sse_load_order();
-    let install_path = Path::new(install_dir.as_ref() as &str).join(Path::new("Data"));
-    let mut music_map: HashMap<String, Vec<u32>> = HashMap::new();
-
-    for plugin_entry in load_order.iter() {
-        let plugin_path = install_path.join(Path::new(plugin_entry));
-        let plugin_file_name = plugin_path.file_name().unwrap().to_str().unwrap();
-        if plugin_file_name != output_name {
-            println!("{}", plugin_file_name);
-            if !plugin_path.exists() {
-                println!("Unable to find {}", plugin_path.to_str().unwrap());
-            } else {
-                let result = parse(plugin_path.as_path());
-                match result {
-                    Ok(plugin) => {
-                        let plugin_music = handle_plugin(&plugin);
-                        if plugin_music.len() > 0 {
-                            for master in plugin.masters {
-                                if !output_masters.contains(&master) {
-                                    output_masters.push(master.clone());
-                                }
-                                output_masters.push(String::from(plugin_file_name));
-                            }
-                            for (editor_id, track_ids) in plugin_music.iter() {
-                                let vec = music_map.entry(editor_id.clone()).or_insert(vec![]);
-                                for track_id in track_ids.iter() {
-                                    if !vec.contains(track_id) {
-                                        vec.push(*track_id);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Err(err) => {
-                        println!("[Error] {}", err);
-                        break;
-                    }
-                }
+    let cli = Cli::parse();
+    let level = match (cli.quiet, cli.verbose) {
+        (true, _) => LevelFilter::Warn,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    if let Err(err) = logger::init(level, cli.log_file.as_deref()) {
+        eprintln!("[Error] {}", err);
+        process::exit(1);
+    }
+    if let Err(err) = run(&cli) {
+        error!("{}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin(name: &str, masters: &[&str]) -> Plugin {
+        let mut plugin = Plugin::new(Path::new(name));
+        plugin.masters = masters.iter().map(|m| String::from(*m)).collect();
+        plugin
+    }
+
+    fn musc(form_id: u32, editor_id: &str, track_ids: &[u32]) -> MUSC {
+        MUSC {
+            form_id,
+            editor_id: String::from(editor_id),
+            flags: 0,
+            priority: 50,
+            ducking: 0,
+            fade_duration: 1.0,
+            track_ids: track_ids.to_vec(),
+        }
+    }
+
+    fn record(versions: &[(&Plugin, MUSC)]) -> (GlobalFormId, RecordVersions) {
+        let versions: Vec<MusicVersion> = versions
+            .iter()
+            .map(|(plugin, musc)| MusicVersion::new(plugin, musc))
+            .collect();
+        (versions[0].form_id.clone(), RecordVersions::new(versions))
+    }
+
+    #[test]
+    fn unchanged_records_add_no_masters() {
+        let skyrim = plugin("Skyrim.esm", &[]);
+        let dawnguard = plugin("Dawnguard.esm", &["Skyrim.esm"]);
+        let unrelated = plugin("Unrelated.esp", &["Skyrim.esm"]);
+        let tracks = plugin("Tracks.esp", &["Skyrim.esm"]);
+        let itm = plugin("Itm.esp", &["Skyrim.esm"]);
+        let other = plugin("Other.esp", &["Skyrim.esm"]);
+        let contributions = BTreeMap::from([
+            // Both overrides add a track, with an ITM between them.
+            record(&[
+                (&skyrim, musc(0x100, "MUSCombatBoss", &[0x200])),
+                (&other, musc(0x100, "MUSCombatBoss", &[0x200, 0x203])),
+                (&itm, musc(0x100, "MUSCombatBoss", &[0x200])),
+                (&tracks, musc(0x100, "MUSCombatBoss", &[0x200, 0x0100_0800])),
+            ]),
+            // Nobody overrides these.
+            record(&[(&skyrim, musc(0x101, "MUSDungeon", &[0x201]))]),
+            record(&[(&dawnguard, musc(0x0100_0300, "MUSDLC1", &[0x201]))]),
+            record(&[(&unrelated, musc(0x0100_0900, "MUSNew", &[0x0100_0901]))]),
+            // Overridden once, by a version the game already uses.
+            record(&[
+                (&skyrim, musc(0x102, "MUSTavern", &[0x202])),
+                (&unrelated, musc(0x102, "MUSTavern", &[0x202, 0x0100_0901])),
+            ]),
+        ]);
+        let mut report = Report::new(None);
+        let (merged, _) = merge_records(&contributions, &Config::default(), &mut report);
+        assert_eq!(
+            merged.keys().collect::<Vec<_>>(),
+            [&GlobalFormId::new("Skyrim.esm", 0x100)]
+        );
+        let load_order: Vec<LoadOrderEntry> = [
+            "Skyrim.esm",
+            "Dawnguard.esm",
+            "Other.esp",
+            "Itm.esp",
+            "Tracks.esp",
+            "Unrelated.esp",
+        ]
+        .into_iter()
+        .map(|name| LoadOrderEntry::new(name, true))
+        .collect();
+        let patches = split_patches(&load_order, "patch.esp", merged, 255).unwrap();
+        assert_eq!(patches.len(), 1);
+        // Only the plugins defining the merged record and its tracks.
+        assert_eq!(patches[0].masters, ["Skyrim.esm", "Tracks.esp"]);
+    }
+
+    #[test]
+    fn excluded_plugins_still_win() {
+        let skyrim = plugin("Skyrim.esm", &[]);
+        let a = plugin("A.esp", &["Skyrim.esm"]);
+        let x = plugin("X.esp", &["Skyrim.esm"]);
+        let versions = [
+            (&skyrim, musc(0x100, "MUSCombatBoss", &[0x201, 0x202])),
+            (&a, musc(0x100, "MUSCombatBoss", &[0x201, 0x202, 0x203])),
+            (&x, musc(0x100, "MUSCombatBoss", &[0x201])),
+        ];
+        let config = Config {
+            exclude_plugins: vec![String::from("x.esp")],
+            ..Config::default()
+        };
+        let versions: Vec<MusicVersion> = versions
+            .iter()
+            .map(|(plugin, musc)| MusicVersion::new(plugin, musc))
+            .collect();
+        let form_id = versions[0].form_id.clone();
+        let record = RecordVersions::excluding(versions, &config.exclude_plugins);
+        assert_eq!(record.winner().plugin, "X.esp");
+        assert_eq!(record.last().plugin, "A.esp");
+
+        let contributions = BTreeMap::from([(form_id.clone(), record)]);
+        let mut report = Report::new(None);
+        let (merged, _) = merge_records(&contributions, &config, &mut report);
+        // X.esp's version isn't merged, but the patch has to undo it.
+        let tracks: Vec<u32> = merged[&form_id]
+            .track_ids
+            .iter()
+            .map(|id| id.object_id)
+            .collect();
+        assert_eq!(tracks, [0x201, 0x202, 0x203]);
+    }
+
+    #[test]
+    fn patch_names_round_trip() {
+        for output in ["music_merge_patch.esp", "Patch.ESL", "patch"] {
+            for index in 0..12 {
+                let name = patch_name(output, index);
+                assert!(is_patch(output, &name), "{}", name);
+                assert!(is_patch(output, &name.to_uppercase()), "{}", name);
             }
         }
+        assert_eq!(patch_name("patch.esp", 0), "patch.esp");
+        assert_eq!(patch_name("patch.esp", 1), "patch_2.esp");
+        assert_eq!(patch_name("patch", 2), "patch_3");
+    }
+
+    #[test]
+    fn other_plugins_are_not_patches() {
+        for name in [
+            "patch.esm",
+            "patch_1.esp",
+            "patch_x.esp",
+            "patch_2.esp.bak",
+            "patch_.esp",
+            "other_patch.esp",
+        ] {
+            assert!(!is_patch("patch.esp", name), "{}", name);
+        }
+        assert!(!is_patch("patch", "patch_2.esp"));
+        assert!(!is_patch("patch", "patch.esp"));
     }
-    let mut output_plugin = Plugin::new(&install_path.join(Path::new(output_name)));
-    output_plugin.author = String::from("ESMusicMerger");
-    output_plugin.description = String::from(format!(
-        "Collection of music from {:?}",
-        output_plugin.masters
-    ));
-    output_plugin.masters = output_masters.clone();
 
-    println!("{:?}", output_masters);
-    //println!("{:?}", music_map);
+    #[test]
+    fn patches_split_at_max_masters() {
+        let merged_record = |object_id: u32, track_plugins: &[&str]| {
+            let track_ids = track_plugins
+                .iter()
+                .map(|plugin| GlobalFormId::new(*plugin, 0x800))
+                .collect();
+            (
+                GlobalFormId::new("Skyrim.esm", object_id),
+                MergedRecord {
+                    musc: musc(object_id, "MUSTest", &[]),
+                    track_ids,
+                    conflicts: vec![],
+                },
+            )
+        };
+        let load_order: Vec<LoadOrderEntry> = ["Skyrim.esm", "A.esp", "B.esp", "C.esp", "D.esp"]
+            .into_iter()
+            .map(|name| LoadOrderEntry::new(name, true))
+            .collect();
+        let merged = BTreeMap::from([
+            merged_record(0x100, &["B.esp"]),
+            // Exactly max_masters still fits.
+            merged_record(0x101, &["A.esp"]),
+            // One more doesn't.
+            merged_record(0x102, &["C.esp"]),
+            merged_record(0x103, &["C.esp", "D.esp"]),
+            merged_record(0x104, &["A.esp"]),
+        ]);
+        let patches = split_patches(&load_order, "patch.esp", merged, 3).unwrap();
+        let patches: Vec<(&str, Vec<u32>, Vec<&str>)> = patches
+            .iter()
+            .map(|patch| {
+                (
+                    patch.name.as_str(),
+                    patch.records.keys().map(|id| id.object_id).collect(),
+                    patch.masters.iter().map(String::as_str).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            patches,
+            [
+                (
+                    "patch.esp",
+                    vec![0x100, 0x101],
+                    vec!["Skyrim.esm", "A.esp", "B.esp"]
+                ),
+                (
+                    "patch_2.esp",
+                    vec![0x102, 0x103],
+                    vec!["Skyrim.esm", "C.esp", "D.esp"]
+                ),
+                ("patch_3.esp", vec![0x104], vec!["Skyrim.esm", "A.esp"]),
+            ]
+        );
+
+        let merged = BTreeMap::from([merged_record(0x100, &["A.esp", "B.esp", "C.esp"])]);
+        assert!(split_patches(&load_order, "patch.esp", merged, 3).is_err());
+    }
 }
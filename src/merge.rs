@@ -0,0 +1,664 @@
+//! Merging the versions of a MUSC record that different plugins have.
+
+use std::fmt;
+use std::str::FromStr;
+
+use log::warn;
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::records::MUSC;
+use crate::report::{FieldChange, FieldConflict};
+use crate::{GlobalFormId, Plugin};
+
+/// One plugin's version of a MUSC record.
+#[derive(Debug, Clone)]
+pub struct MusicVersion {
+    pub plugin: String,
+    pub form_id: GlobalFormId,
+    pub musc: MUSC,
+    /// The record's tracks, resolved against the plugin's masters.
+    pub track_ids: Vec<GlobalFormId>,
+}
+
+impl MusicVersion {
+    pub fn new(plugin: &Plugin, musc: &MUSC) -> Self {
+        MusicVersion {
+            plugin: plugin.name.clone(),
+            form_id: plugin.resolve_form_id(musc.form_id),
+            musc: musc.clone(),
+            // Track FormIDs are relative to this plugin's masters, so resolve
+            // them before comparing them against other plugins' tracks.
+            track_ids: musc
+                .track_ids
+                .iter()
+                .map(|id| plugin.resolve_form_id(*id))
+                .collect(),
+        }
+    }
+
+    /// True if the versions have the same fields and tracks.
+    pub fn is_identical(&self, other: &MusicVersion) -> bool {
+        same_fields(&self.musc, &other.musc) && self.track_ids == other.track_ids
+    }
+}
+
+/// Every version of a record, in load order.
+#[derive(Debug, Clone)]
+pub struct RecordVersions {
+    /// The versions to merge, which are all but the ITMs and the versions
+    /// of excluded plugins.
+    pub versions: Vec<MusicVersion>,
+    /// Index of the version the others are compared against.
+    pub base: usize,
+    /// Overrides identical to the defining plugin's version (ITMs), which
+    /// are left out of `versions`.
+    pub identical: Vec<MusicVersion>,
+    /// Every version, ITMs and excluded plugins included.
+    pub all: Vec<MusicVersion>,
+}
+
+impl RecordVersions {
+    /// The base is the version in the plugin defining the record. If that
+    /// plugin isn't loaded, the earliest version we have is the best guess,
+    /// and no overrides are taken as identical to it.
+    pub fn new(versions: Vec<MusicVersion>) -> Self {
+        Self::excluding(versions, &[])
+    }
+
+    /// Like `new`, but leaving the versions of the `excluded` plugins out
+    /// of the merge. They still count towards the winner, as the game loads
+    /// them all the same. At least one version must not be excluded.
+    pub fn excluding(all: Vec<MusicVersion>, excluded: &[String]) -> Self {
+        let versions: Vec<MusicVersion> = all
+            .iter()
+            .filter(|version| !contains_ignore_case(excluded, &version.plugin))
+            .cloned()
+            .collect();
+        let form_id = versions[0].form_id.clone();
+        let mut record = RecordVersions {
+            versions: vec![],
+            base: 0,
+            identical: vec![],
+            all,
+        };
+        match versions
+            .iter()
+            .position(|version| version.plugin.eq_ignore_ascii_case(&form_id.plugin))
+        {
+            Some(base) => {
+                for (index, version) in versions.iter().enumerate() {
+                    if index == base {
+                        record.base = record.versions.len();
+                    } else if version.is_identical(&versions[base]) {
+                        record.identical.push(version.clone());
+                        continue;
+                    }
+                    record.versions.push(version.clone());
+                }
+            }
+            None => {
+                let reason = if contains_ignore_case(excluded, &form_id.plugin) {
+                    "is excluded"
+                } else {
+                    "isn't loaded"
+                };
+                warn!(
+                    "{} {}, comparing overrides of {} to {} instead",
+                    form_id.plugin, reason, form_id, versions[0].plugin
+                );
+                record.versions = versions;
+            }
+        }
+        let editor_id = &record.base().musc.editor_id;
+        for version in record.versions.iter() {
+            if version.musc.editor_id != *editor_id {
+                warn!(
+                    "{} renames {} [{}] to {}",
+                    version.plugin, editor_id, form_id, version.musc.editor_id
+                );
+            }
+        }
+        record
+    }
+
+    pub fn base(&self) -> &MusicVersion {
+        &self.versions[self.base]
+    }
+
+    /// Every version but the base, in load order.
+    pub fn overrides(&self) -> Vec<&MusicVersion> {
+        self.versions
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != self.base)
+            .map(|(_, version)| version)
+            .collect()
+    }
+
+    /// The version the game would use without a patch, which can be an ITM
+    /// or an excluded plugin's.
+    pub fn winner(&self) -> &MusicVersion {
+        self.all.last().unwrap()
+    }
+
+    /// The last version to merge. Strategies take what they don't merge
+    /// from it.
+    pub fn last(&self) -> &MusicVersion {
+        self.versions.last().unwrap()
+    }
+
+    /// True if the winner is an ITM loading after real overrides, so the
+    /// game undoes their changes.
+    pub fn itm_wins(&self) -> bool {
+        let winner = self.winner();
+        !self.overrides().is_empty()
+            && self
+                .identical
+                .iter()
+                .any(|version| version.plugin.eq_ignore_ascii_case(&winner.plugin))
+    }
+
+    /// Just the base and the versions in `plugins`, leaving out ITMs.
+    pub fn only(&self, plugins: &[String]) -> RecordVersions {
+        let mut record = RecordVersions {
+            versions: vec![],
+            base: 0,
+            identical: vec![],
+            all: vec![],
+        };
+        for (index, version) in self.versions.iter().enumerate() {
+            if index == self.base {
+                record.base = record.versions.len();
+            } else if !contains_ignore_case(plugins, &version.plugin) {
+                continue;
+            }
+            record.versions.push(version.clone());
+        }
+        record.all = record.versions.clone();
+        record
+    }
+}
+
+fn same_fields(a: &MUSC, b: &MUSC) -> bool {
+    a.editor_id == b.editor_id
+        && a.flags == b.flags
+        && a.priority == b.priority
+        && a.ducking == b.ducking
+        && a.fade_duration == b.fade_duration
+}
+
+fn contains_ignore_case(names: &[String], name: &str) -> bool {
+    names.iter().any(|n| n.eq_ignore_ascii_case(name))
+}
+
+/// A merged record, with its FormIDs still global.
+#[derive(Debug, Clone)]
+pub struct MergedRecord {
+    pub musc: MUSC,
+    pub track_ids: Vec<GlobalFormId>,
+    /// Fields that overrides changed to different values.
+    pub conflicts: Vec<FieldConflict>,
+}
+
+impl MergedRecord {
+    /// A version of the record, taken as it is.
+    pub fn from_version(version: &MusicVersion) -> Self {
+        MergedRecord {
+            musc: version.musc.clone(),
+            track_ids: version.track_ids.clone(),
+            conflicts: vec![],
+        }
+    }
+
+    /// True if the record has the same fields and tracks as `version`.
+    pub fn is_identical(&self, version: &MusicVersion) -> bool {
+        same_fields(&self.musc, &version.musc) && self.track_ids == version.track_ids
+    }
+}
+
+/// A way of merging the versions of a record into one.
+pub trait MergeStrategy {
+    fn merge(&self, record: &RecordVersions) -> MergedRecord;
+}
+
+/// Keep every track any version lists, in load order. Everything else
+/// comes from the last version.
+pub struct Union;
+
+impl MergeStrategy for Union {
+    fn merge(&self, record: &RecordVersions) -> MergedRecord {
+        let mut merged = MergedRecord::from_version(record.last());
+        merged.track_ids.clear();
+        for track in record.versions.iter().flat_map(|v| v.track_ids.iter()) {
+            if !merged.track_ids.contains(track) {
+                merged.track_ids.push(track.clone());
+            }
+        }
+        merged
+    }
+}
+
+/// Apply every override's changes to the base record. Tracks any override
+/// removed are dropped and tracks any override added are kept, while each
+/// other field comes from the last override to change it.
+pub struct ThreeWay;
+
+impl MergeStrategy for ThreeWay {
+    fn merge(&self, record: &RecordVersions) -> MergedRecord {
+        let base = &record.base().musc;
+        let overrides = record.overrides();
+        let track_lists: Vec<&[GlobalFormId]> = overrides
+            .iter()
+            .map(|version| version.track_ids.as_slice())
+            .collect();
+        let mut conflicts = vec![];
+        // Anything we don't merge, like the editor ID, comes from the last version.
+        let mut musc = record.last().musc.clone();
+        musc.flags = merge_field("flags", base, &overrides, |m| m.flags, &mut conflicts);
+        musc.priority = merge_field("priority", base, &overrides, |m| m.priority, &mut conflicts);
+        musc.ducking = merge_field("ducking", base, &overrides, |m| m.ducking, &mut conflicts);
+        musc.fade_duration = merge_field(
+            "fade_duration",
+            base,
+            &overrides,
+            |m| m.fade_duration,
+            &mut conflicts,
+        );
+        MergedRecord {
+            musc,
+            track_ids: merge_tracks(&record.base().track_ids, &track_lists),
+            conflicts,
+        }
+    }
+}
+
+/// Take the last version as it is, as the game would without a patch if
+/// it weren't for ITMs and excluded plugins.
+pub struct LastWins;
+
+impl MergeStrategy for LastWins {
+    fn merge(&self, record: &RecordVersions) -> MergedRecord {
+        MergedRecord::from_version(record.last())
+    }
+}
+
+/// Take one plugin's version as it is, wherever it is in the load order.
+/// Records the plugin doesn't have are merged with `ThreeWay`.
+pub struct PreferPlugin(pub String);
+
+impl MergeStrategy for PreferPlugin {
+    fn merge(&self, record: &RecordVersions) -> MergedRecord {
+        match record
+            .versions
+            .iter()
+            .find(|version| version.plugin.eq_ignore_ascii_case(&self.0))
+        {
+            Some(version) => MergedRecord::from_version(version),
+            None => ThreeWay.merge(record),
+        }
+    }
+}
+
+/// What came of merging a record with a config's rules.
+pub enum MergeOutcome {
+    /// The merged record, and the names of the rules that applied to it.
+    Merged(MergedRecord, Vec<String>),
+    /// The named rule said to leave the record out of the patch.
+    Skipped(String),
+}
+
+/// Merge a record with the strategy and rules of `config`. When several
+/// rules set the same thing, the last one wins, while any rule skipping the
+/// record skips it.
+pub fn merge_record(record: &RecordVersions, config: &Config) -> MergeOutcome {
+    let rules = config.rules_for(&record.base().form_id, &record.last().musc.editor_id);
+    if let Some((name, _)) = rules.iter().find(|(_, rule)| rule.skip) {
+        return MergeOutcome::Skipped(name.clone());
+    }
+    let mut strategy = &config.strategy;
+    let mut only_plugins: Option<&[String]> = None;
+    for (_, rule) in rules.iter() {
+        if let Some(ref rule_strategy) = rule.strategy {
+            strategy = rule_strategy;
+        }
+        if !rule.only_plugins.is_empty() {
+            only_plugins = Some(&rule.only_plugins);
+        }
+    }
+    let mut merged = match only_plugins {
+        Some(plugins) => {
+            let record = record.only(plugins);
+            let mut merged = strategy.merge_strategy().merge(&record);
+            merged.track_ids.retain(|track| {
+                record.versions.iter().any(|version| {
+                    contains_ignore_case(plugins, &version.plugin)
+                        && version.track_ids.contains(track)
+                })
+            });
+            merged
+        }
+        None => strategy.merge_strategy().merge(record),
+    };
+    for track in rules.iter().flat_map(|(_, rule)| rule.append_tracks.iter()) {
+        if !merged.track_ids.contains(track) {
+            merged.track_ids.push(track.clone());
+        }
+    }
+    MergeOutcome::Merged(merged, rules.into_iter().map(|(name, _)| name).collect())
+}
+
+/// Merge each override's changes to a track list into the base record's.
+/// Tracks any override removed from the base are dropped, and tracks any
+/// override added are appended in load order.
+fn merge_tracks(base: &[GlobalFormId], overrides: &[&[GlobalFormId]]) -> Vec<GlobalFormId> {
+    let mut tracks: Vec<GlobalFormId> = base
+        .iter()
+        .filter(|track| overrides.iter().all(|o| o.contains(track)))
+        .cloned()
+        .collect();
+    for track in overrides.iter().flat_map(|o| o.iter()) {
+        if !base.contains(track) && !tracks.contains(track) {
+            tracks.push(track.clone());
+        }
+    }
+    tracks
+}
+
+/// Take a field from the last override to change it from the base. When
+/// overrides change it to different values, the conflict is noted.
+fn merge_field<T>(
+    field: &str,
+    base: &MUSC,
+    overrides: &[&MusicVersion],
+    value: fn(&MUSC) -> T,
+    conflicts: &mut Vec<FieldConflict>,
+) -> T
+where
+    T: Copy + PartialEq + Into<serde_json::Value>,
+{
+    let changes: Vec<&MusicVersion> = overrides
+        .iter()
+        .filter(|version| value(&version.musc) != value(base))
+        .copied()
+        .collect();
+    let merged = match changes.last() {
+        Some(version) => value(&version.musc),
+        None => value(base),
+    };
+    if changes.iter().any(|version| value(&version.musc) != merged) {
+        conflicts.push(FieldConflict {
+            field: String::from(field),
+            changes: changes
+                .iter()
+                .map(|version| FieldChange {
+                    plugin: version.plugin.clone(),
+                    value: value(&version.musc).into(),
+                })
+                .collect(),
+        });
+    }
+    merged
+}
+
+/// The built-in strategies, by name. Written as `union`, `three-way`,
+/// `last-wins` or `prefer:<plugin>` on the command line and in config files.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Strategy {
+    Union,
+    #[default]
+    ThreeWay,
+    LastWins,
+    PreferPlugin(String),
+}
+
+impl Strategy {
+    pub fn merge_strategy(&self) -> Box<dyn MergeStrategy> {
+        match self {
+            Strategy::Union => Box::new(Union),
+            Strategy::ThreeWay => Box::new(ThreeWay),
+            Strategy::LastWins => Box::new(LastWins),
+            Strategy::PreferPlugin(plugin) => Box::new(PreferPlugin(plugin.clone())),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Strategy::Union => write!(f, "union"),
+            Strategy::ThreeWay => write!(f, "three-way"),
+            Strategy::LastWins => write!(f, "last-wins"),
+            Strategy::PreferPlugin(plugin) => write!(f, "prefer:{}", plugin),
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "union" => Ok(Strategy::Union),
+            "three-way" => Ok(Strategy::ThreeWay),
+            "last-wins" => Ok(Strategy::LastWins),
+            _ => match s.split_once(':') {
+                Some((prefix, plugin))
+                    if prefix.eq_ignore_ascii_case("prefer") && !plugin.is_empty() =>
+                {
+                    Ok(Strategy::PreferPlugin(String::from(plugin)))
+                }
+                _ => Err(format!(
+                    "Unknown merge strategy `{}`. Expected union, three-way, last-wins \
+                    or prefer:<plugin>.",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for Strategy {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Rule;
+
+    fn tracks(object_ids: &[u32]) -> Vec<GlobalFormId> {
+        object_ids
+            .iter()
+            .map(|id| GlobalFormId::new("Skyrim.esm", *id))
+            .collect()
+    }
+
+    fn merged_tracks(base: &[u32], overrides: &[&[u32]]) -> Vec<GlobalFormId> {
+        let overrides: Vec<Vec<GlobalFormId>> = overrides.iter().map(|o| tracks(o)).collect();
+        let overrides: Vec<&[GlobalFormId]> = overrides.iter().map(|o| o.as_slice()).collect();
+        merge_tracks(&tracks(base), &overrides)
+    }
+
+    fn version_with_tracks(plugin: &str, object_ids: &[u32]) -> MusicVersion {
+        MusicVersion {
+            track_ids: tracks(object_ids),
+            ..version(plugin, 50)
+        }
+    }
+
+    fn version(plugin: &str, priority: u16) -> MusicVersion {
+        MusicVersion {
+            plugin: String::from(plugin),
+            form_id: GlobalFormId::new("Skyrim.esm", 0x100),
+            musc: MUSC {
+                form_id: 0x100,
+                editor_id: String::from("MUSCombatBoss"),
+                flags: 0,
+                priority,
+                ducking: 0,
+                fade_duration: 1.0,
+                track_ids: vec![],
+            },
+            track_ids: vec![],
+        }
+    }
+
+    /// The merged priority, and each change noted as conflicting.
+    fn merged_priority(base: u16, overrides: &[(&str, u16)]) -> (u16, Vec<(String, u16)>) {
+        let base = version("Skyrim.esm", base);
+        let overrides: Vec<MusicVersion> = overrides
+            .iter()
+            .map(|(plugin, priority)| version(plugin, *priority))
+            .collect();
+        let overrides: Vec<&MusicVersion> = overrides.iter().collect();
+        let mut conflicts = vec![];
+        let priority = merge_field(
+            "priority",
+            &base.musc,
+            &overrides,
+            |m| m.priority,
+            &mut conflicts,
+        );
+        let changes = conflicts
+            .iter()
+            .inspect(|conflict| assert_eq!(conflict.field, "priority"))
+            .flat_map(|conflict| conflict.changes.iter())
+            .map(|change| (change.plugin.clone(), change.value.as_u64().unwrap() as u16))
+            .collect();
+        (priority, changes)
+    }
+
+    #[test]
+    fn merge_tracks_keeps_the_base_without_overrides() {
+        assert_eq!(merged_tracks(&[1, 2], &[]), tracks(&[1, 2]));
+        assert_eq!(merged_tracks(&[1, 2], &[&[1, 2], &[2, 1]]), tracks(&[1, 2]));
+    }
+
+    #[test]
+    fn merge_tracks_appends_additions_in_load_order() {
+        assert_eq!(
+            merged_tracks(&[1], &[&[1, 3], &[4, 1], &[1, 3]]),
+            tracks(&[1, 3, 4])
+        );
+    }
+
+    #[test]
+    fn merge_tracks_drops_removals() {
+        assert_eq!(
+            merged_tracks(&[1, 2, 3], &[&[1, 3], &[1, 2, 3, 4]]),
+            tracks(&[1, 3, 4])
+        );
+        assert_eq!(merged_tracks(&[1, 2], &[&[], &[1, 2]]), tracks(&[]));
+    }
+
+    #[test]
+    fn merge_tracks_removal_wins_over_a_later_re_add() {
+        // Compared to the base, a plugin adding back a track an earlier one
+        // removed looks the same as one that never removed it.
+        assert_eq!(merged_tracks(&[1, 2], &[&[1], &[1, 2]]), tracks(&[1]));
+        // Tracks the base doesn't have are kept if anyone adds them, even if
+        // a later plugin built on the earlier one removes them again.
+        assert_eq!(merged_tracks(&[1], &[&[1, 5], &[1]]), tracks(&[1, 5]));
+    }
+
+    #[test]
+    fn merge_field_keeps_the_base_when_nothing_changes_it() {
+        assert_eq!(merged_priority(50, &[]), (50, vec![]));
+        assert_eq!(
+            merged_priority(50, &[("A.esp", 50), ("B.esp", 50)]),
+            (50, vec![])
+        );
+    }
+
+    #[test]
+    fn merge_field_takes_the_last_change() {
+        // A later override leaving the field alone doesn't undo a change.
+        assert_eq!(
+            merged_priority(50, &[("A.esp", 60), ("B.esp", 50)]),
+            (60, vec![])
+        );
+        // Changing it to the same value isn't a conflict.
+        assert_eq!(
+            merged_priority(50, &[("A.esp", 60), ("B.esp", 60)]),
+            (60, vec![])
+        );
+    }
+
+    #[test]
+    fn merge_field_notes_conflicting_changes() {
+        assert_eq!(
+            merged_priority(50, &[("A.esp", 60), ("B.esp", 50), ("C.esp", 70)]),
+            (
+                70,
+                vec![(String::from("A.esp"), 60), (String::from("C.esp"), 70)]
+            )
+        );
+    }
+
+    #[test]
+    fn later_rules_win_and_skip_beats_everything() {
+        let record = RecordVersions::new(vec![
+            version_with_tracks("Skyrim.esm", &[1, 2]),
+            version_with_tracks("A.esp", &[1, 2, 3]),
+            version_with_tracks("B.esp", &[1, 2, 4]),
+        ]);
+        let boss = |rule: Rule| Rule {
+            editor_id: Some(String::from("muscombatboss")),
+            ..rule
+        };
+        let mut config = Config {
+            strategy: Strategy::Union,
+            exclude_plugins: vec![],
+            rules: vec![
+                boss(Rule {
+                    strategy: Some(Strategy::LastWins),
+                    only_plugins: vec![String::from("B.esp")],
+                    append_tracks: tracks(&[9]),
+                    ..Rule::default()
+                }),
+                Rule {
+                    form_id: Some(GlobalFormId::new("skyrim.esm", 0x100)),
+                    strategy: Some(Strategy::ThreeWay),
+                    only_plugins: vec![String::from("a.esp")],
+                    ..Rule::default()
+                },
+                // Doesn't match, so changes nothing.
+                Rule {
+                    editor_id: Some(String::from("MUSDungeon")),
+                    strategy: Some(Strategy::LastWins),
+                    ..Rule::default()
+                },
+                boss(Rule {
+                    name: Some(String::from("extra tracks")),
+                    append_tracks: tracks(&[9, 8]),
+                    ..Rule::default()
+                }),
+            ],
+        };
+        match merge_record(&record, &config) {
+            MergeOutcome::Merged(merged, rules) => {
+                // Three-way between the base and A.esp only, plus both
+                // rules' tracks.
+                assert_eq!(merged.track_ids, tracks(&[1, 2, 3, 9, 8]));
+                assert_eq!(rules, ["rule 1", "rule 2", "extra tracks"]);
+            }
+            MergeOutcome::Skipped(rule) => panic!("skipped by {}", rule),
+        }
+
+        config.rules.push(boss(Rule {
+            name: Some(String::from("no boss music")),
+            skip: true,
+            ..Rule::default()
+        }));
+        match merge_record(&record, &config) {
+            MergeOutcome::Skipped(rule) => assert_eq!(rule, "no boss music"),
+            MergeOutcome::Merged(..) => panic!("not skipped"),
+        }
+    }
+}
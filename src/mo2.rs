@@ -0,0 +1,365 @@
+//! Support for Mod Organizer 2, which leaves the game's Data directory
+//! alone and overlays each mod's folder on top of it at runtime.
+
+use std::fs;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+
+use log::warn;
+
+use crate::find_file_ignore_case;
+use crate::Game;
+use crate::GameSettings;
+use crate::InstallSource;
+use crate::InstalledGame;
+use crate::LoadOrderMethod;
+
+/// Where to put a file written into an MO2 instance.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mo2Output {
+    /// The overwrite folder, where MO2 collects files created by tools.
+    Overwrite,
+    /// A mod folder of the given name, created and enabled if needed.
+    Mod(String),
+}
+
+/// A mod in a profile's modlist.txt.
+#[derive(Debug, Clone)]
+pub struct Mo2Mod {
+    pub name: String,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Mo2Instance {
+    pub base_path: PathBuf,
+    pub game: Game,
+    pub game_path: PathBuf,
+    pub mods_path: PathBuf,
+    pub profiles_path: PathBuf,
+    pub overwrite_path: PathBuf,
+    pub profile: String,
+}
+
+/// MO2 names games after its game plugins rather than the game itself.
+fn game_from_mo2_name(name: &str) -> Option<Game> {
+    match name {
+        "Morrowind" => Some(Game::Morrowind),
+        "Oblivion" => Some(Game::Oblivion),
+        "Skyrim" => Some(Game::Skyrim),
+        "Skyrim Special Edition" => Some(Game::SkyrimSE),
+        "Skyrim VR" => Some(Game::SkyrimVR),
+        "Fallout 3" => Some(Game::Fallout3),
+        "New Vegas" | "TTW" => Some(Game::FalloutNV),
+        "Fallout 4" => Some(Game::Fallout4),
+        "Fallout 4 VR" => Some(Game::Fallout4VR),
+        _ => None,
+    }
+}
+
+/// Qt writes some values as `@ByteArray(...)` and doubles backslashes.
+fn ini_value(value: &str) -> String {
+    let value = value.trim();
+    let value = value
+        .strip_prefix("@ByteArray(")
+        .and_then(|v| v.strip_suffix(')'))
+        .unwrap_or(value);
+    value.replace("\\\\", "\\")
+}
+
+/// Read the `key=value` pairs of ModOrganizer.ini as `section/key`.
+fn read_ini(p: &Path) -> Result<Vec<(String, String)>, Error> {
+    let bytes = fs::read(p)?;
+    let mut section = String::new();
+    let mut values: Vec<(String, String)> = vec![];
+    for line in String::from_utf8_lossy(&bytes).lines() {
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = String::from(name);
+        } else if let Some((key, value)) = line.split_once('=') {
+            values.push((format!("{}/{}", section, key.trim()), ini_value(value)));
+        }
+    }
+    Ok(values)
+}
+
+fn invalid<S>(message: S) -> Error
+where
+    S: Into<String>,
+{
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+impl Mo2Instance {
+    /// Open the instance in `base_path`, using `profile` or whichever
+    /// profile was last selected in MO2.
+    pub fn open(base_path: &Path, profile: Option<&str>) -> Result<Self, Error> {
+        let ini = read_ini(&base_path.join("ModOrganizer.ini"))?;
+        let get = |key: &str| {
+            ini.iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                .map(|(_, v)| v.as_str())
+                .filter(|v| !v.is_empty())
+        };
+        let game_name = get("General/gameName").ok_or_else(|| invalid("No game set"))?;
+        let game = game_from_mo2_name(game_name)
+            .ok_or_else(|| invalid(format!("Unsupported game `{}`", game_name)))?;
+        let game_path = get("General/gamePath").ok_or_else(|| invalid("No game path set"))?;
+        let base_path = match get("Settings/base_directory") {
+            Some(dir) => PathBuf::from(dir),
+            None => base_path.to_owned(),
+        };
+        let directory = |key: &str, default: &str| -> PathBuf {
+            match get(key) {
+                Some(dir) => PathBuf::from(dir.replace("%BASE_DIR%", &base_path.to_string_lossy())),
+                None => base_path.join(default),
+            }
+        };
+        let profile = match profile.or_else(|| get("General/selected_profile")) {
+            Some(profile) => String::from(profile),
+            None => String::from("Default"),
+        };
+        Ok(Mo2Instance {
+            game,
+            game_path: PathBuf::from(game_path),
+            mods_path: directory("Settings/mod_directory", "mods"),
+            profiles_path: directory("Settings/profiles_directory", "profiles"),
+            overwrite_path: directory("Settings/overwrite_directory", "overwrite"),
+            profile,
+            base_path,
+        })
+    }
+
+    /// Look for a portable instance in the working directory or any
+    /// directory above the executable, which is where tools run from
+    /// MO2 usually live.
+    pub fn detect() -> Option<Self> {
+        let mut candidates: Vec<PathBuf> = vec![];
+        if let Ok(dir) = std::env::current_dir() {
+            candidates.push(dir);
+        }
+        if let Ok(exe) = std::env::current_exe() {
+            candidates.extend(exe.ancestors().skip(1).map(Path::to_owned));
+        }
+        candidates
+            .iter()
+            .filter(|dir| dir.join("ModOrganizer.ini").is_file())
+            .find_map(|dir| {
+                Self::open(dir, None)
+                    .map_err(|e| {
+                        warn!(
+                            "Unable to open Mod Organizer 2 instance at `{}`: {}",
+                            dir.display(),
+                            e
+                        )
+                    })
+                    .ok()
+            })
+    }
+
+    pub fn profile_path(&self) -> PathBuf {
+        self.profiles_path.join(&self.profile)
+    }
+
+    /// The profile's mods, highest priority first, which is the reverse
+    /// of how MO2 displays them.
+    pub fn mod_list(&self) -> Result<Vec<Mo2Mod>, Error> {
+        let bytes = fs::read(self.profile_path().join("modlist.txt"))?;
+        Ok(String::from_utf8_lossy(&bytes)
+            .lines()
+            .filter_map(|line| {
+                // `*` marks files MO2 doesn't manage, such as DLC, which
+                // already live in the Data directory.
+                let (enabled, name) = match line.trim().split_at_checked(1)? {
+                    ("+", name) => (true, name),
+                    ("-", name) => (false, name),
+                    _ => return None,
+                };
+                Some(Mo2Mod {
+                    name: String::from(name),
+                    enabled,
+                })
+            })
+            .collect())
+    }
+
+    /// Directories MO2 layers over the Data directory, highest priority
+    /// first: the overwrite folder, then each enabled mod.
+    pub fn data_overlays(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut overlays = vec![self.overwrite_path.clone()];
+        for mo2_mod in self.mod_list()? {
+            if mo2_mod.enabled {
+                overlays.push(self.mods_path.join(mo2_mod.name));
+            }
+        }
+        Ok(overlays)
+    }
+
+    /// Find the file the game will see for a plugin, walking the mods in
+    /// priority order before falling back to the Data directory.
+    pub fn resolve_plugin(&self, name: &str) -> Result<PathBuf, Error> {
+        for overlay in self.data_overlays()? {
+            let path = find_file_ignore_case(&overlay, name);
+            if path.is_file() {
+                return Ok(path);
+            }
+        }
+        let data_path = self.game_path.join(self.game.data_folder_name());
+        Ok(find_file_ignore_case(&data_path, name))
+    }
+
+    /// The instance's game. Its load order lives in the profile rather
+    /// than in `%LOCALAPPDATA%`.
+    pub fn installed_game(&self) -> InstalledGame {
+        InstalledGame {
+            game: self.game,
+            source: InstallSource::ModOrganizer,
+            install_path: self.game_path.clone(),
+            data_path: self.game_path.join(self.game.data_folder_name()),
+            local_path: Some(self.profile_path()),
+        }
+    }
+
+    /// Settings for the instance's game, seeing plugins the way the
+    /// game does when launched through MO2.
+    pub fn game_settings(&self) -> Result<GameSettings, Error> {
+        let mut settings = GameSettings::new(&self.installed_game());
+        settings.data_overlays = self.data_overlays()?;
+        // MO2 manages the order of older games in loadorder.txt and
+        // fixes up timestamps to match in its virtual filesystem.
+        if settings.load_order_method == LoadOrderMethod::Timestamp {
+            settings.load_order_method = LoadOrderMethod::Textfile;
+        }
+        Ok(settings)
+    }
+
+    /// The directory files for `output` go in.
+    pub fn output_dir(&self, output: &Mo2Output) -> PathBuf {
+        match output {
+            Mo2Output::Overwrite => self.overwrite_path.clone(),
+            Mo2Output::Mod(name) => self.mods_path.join(name),
+        }
+    }
+
+    /// Prepare the directory to write into, enabling the mod in the
+    /// current profile if it's a new one.
+    pub fn output_path(&self, output: &Mo2Output) -> Result<PathBuf, Error> {
+        let path = self.output_dir(output);
+        fs::create_dir_all(&path)?;
+        match output {
+            Mo2Output::Overwrite => Ok(path),
+            Mo2Output::Mod(name) => {
+                let mod_list = self.mod_list()?;
+                if !mod_list.iter().any(|m| m.name.eq_ignore_ascii_case(name)) {
+                    // The top of modlist.txt is the highest priority, just
+                    // after the comment MO2 writes at the start.
+                    let list_path = self.profile_path().join("modlist.txt");
+                    let contents = fs::read(&list_path)?;
+                    let contents = String::from_utf8_lossy(&contents);
+                    let mut lines: Vec<&str> = contents.lines().collect();
+                    let position = lines.iter().take_while(|l| l.starts_with('#')).count();
+                    let entry = format!("+{}", name);
+                    lines.insert(position, &entry);
+                    fs::write(&list_path, lines.join("\r\n") + "\r\n")?;
+                }
+                Ok(path)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn instance(dir: &TempDir) -> Mo2Instance {
+        dir.write(
+            "ModOrganizer.ini",
+            format!(
+                "[General]\r\n\
+                 gameName=Skyrim Special Edition\r\n\
+                 gamePath=@ByteArray({})\r\n\
+                 selected_profile=@ByteArray(Testing)\r\n",
+                dir.path().join("Game").display()
+            ),
+        );
+        dir.write(
+            "profiles/Testing/modlist.txt",
+            "# This file was automatically generated by Mod Organizer.\r\n\
+             +High\r\n\
+             -Disabled\r\n\
+             *DLC: Dawnguard\r\n\
+             +Low\r\n",
+        );
+        for file in [
+            "mods/High/Shared.esp",
+            "mods/High/Over.esp",
+            "mods/Low/shared.esp",
+            "mods/Low/Low.esp",
+            "mods/Disabled/Disabled.esp",
+            "overwrite/Over.esp",
+            "Game/Data/Disabled.esp",
+            "Game/Data/Skyrim.esm",
+        ] {
+            dir.write(file, "");
+        }
+        Mo2Instance::open(dir.path(), None).unwrap()
+    }
+
+    #[test]
+    fn reads_the_mod_list_highest_priority_first() {
+        let dir = TempDir::new();
+        let mo2 = instance(&dir);
+        assert_eq!(mo2.game, Game::SkyrimSE);
+        assert_eq!(mo2.profile, "Testing");
+        let mods: Vec<(String, bool)> = mo2
+            .mod_list()
+            .unwrap()
+            .into_iter()
+            .map(|mo2_mod| (mo2_mod.name, mo2_mod.enabled))
+            .collect();
+        // Unmanaged `*` entries and comments aren't mods.
+        assert_eq!(
+            mods,
+            [
+                (String::from("High"), true),
+                (String::from("Disabled"), false),
+                (String::from("Low"), true),
+            ]
+        );
+        assert_eq!(
+            mo2.data_overlays().unwrap(),
+            [
+                dir.path().join("overwrite"),
+                dir.path().join("mods").join("High"),
+                dir.path().join("mods").join("Low"),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolves_plugins_through_the_highest_priority_mod() {
+        let dir = TempDir::new();
+        let mo2 = instance(&dir);
+        let resolve = |name: &str| mo2.resolve_plugin(name).unwrap();
+        assert_eq!(
+            resolve("SHARED.ESP"),
+            dir.path().join("mods/High/Shared.esp")
+        );
+        assert_eq!(resolve("Low.esp"), dir.path().join("mods/Low/Low.esp"));
+        // The overwrite folder beats every mod.
+        assert_eq!(resolve("Over.esp"), dir.path().join("overwrite/Over.esp"));
+        // Disabled mods are passed over for the Data directory.
+        assert_eq!(
+            resolve("Disabled.esp"),
+            dir.path().join("Game/Data/Disabled.esp")
+        );
+        assert_eq!(
+            resolve("Skyrim.esm"),
+            dir.path().join("Game/Data/Skyrim.esm")
+        );
+    }
+}
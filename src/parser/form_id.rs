@@ -0,0 +1,170 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A FormID made independent of the plugin it was read from.
+///
+/// The top byte of a FormID in a plugin file is an index into that plugin's
+/// master list, with the index one past the last master meaning the plugin
+/// itself. Resolving it against the master list gives us the plugin that
+/// actually defines the record, which lets us compare FormIDs read from
+/// different plugins.
+///
+/// Plugin names are compared ignoring ASCII case, as the games do, so a
+/// master list naming `skyrim.esm` resolves to the same FormIDs as one
+/// naming `Skyrim.esm`.
+#[derive(Debug, Clone)]
+pub struct GlobalFormId {
+    /// File name of the plugin defining the record.
+    pub plugin: String,
+    /// The lower 24 bits of the FormID. Light plugins only use the lower 12.
+    pub object_id: u32,
+}
+
+impl GlobalFormId {
+    pub fn new<S>(plugin: S, object_id: u32) -> Self
+    where
+        S: Into<String>,
+    {
+        GlobalFormId {
+            plugin: plugin.into(),
+            object_id: object_id & 0x00FF_FFFF,
+        }
+    }
+
+    fn plugin_bytes(&self) -> impl Iterator<Item = u8> + '_ {
+        self.plugin.bytes().map(|b| b.to_ascii_lowercase())
+    }
+}
+
+impl PartialEq for GlobalFormId {
+    fn eq(&self, other: &Self) -> bool {
+        self.object_id == other.object_id && self.plugin.eq_ignore_ascii_case(&other.plugin)
+    }
+}
+
+impl Eq for GlobalFormId {}
+
+impl Ord for GlobalFormId {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.plugin_bytes()
+            .cmp(other.plugin_bytes())
+            .then(self.object_id.cmp(&other.object_id))
+    }
+}
+
+impl PartialOrd for GlobalFormId {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Hash for GlobalFormId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for b in self.plugin_bytes() {
+            state.write_u8(b);
+        }
+        self.object_id.hash(state);
+    }
+}
+
+impl fmt::Display for GlobalFormId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{:06X}", self.plugin, self.object_id)
+    }
+}
+
+/// Written as `Plugin.esp:XXXXXX`, the same as `Display`.
+impl Serialize for GlobalFormId {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl FromStr for GlobalFormId {
+    type Err = String;
+
+    /// Parse the `Plugin.esp:XXXXXX` form `Display` writes.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once(':') {
+            Some((plugin, object_id)) if !plugin.is_empty() => {
+                match u32::from_str_radix(object_id, 16) {
+                    Ok(object_id) if object_id <= 0x00FF_FFFF => {
+                        Ok(GlobalFormId::new(plugin, object_id))
+                    }
+                    _ => Err(format!("Invalid object ID in FormID `{}`", s)),
+                }
+            }
+            _ => Err(format!(
+                "Expected a FormID like `Skyrim.esm:0001AB`, got `{}`",
+                s
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for GlobalFormId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashSet};
+    use std::path::Path;
+
+    use super::*;
+    use crate::Plugin;
+
+    #[test]
+    fn mixed_case_masters_resolve_to_the_same_form_id() {
+        let mut upper = Plugin::new(Path::new("Upper.esp"));
+        upper.masters = vec![String::from("Skyrim.esm")];
+        let mut lower = Plugin::new(Path::new("Lower.esp"));
+        lower.masters = vec![String::from("skyrim.esm")];
+        let a = upper.resolve_form_id(0x0000_0100);
+        let b = lower.resolve_form_id(0x0000_0100);
+        assert_eq!(a, b);
+        assert_eq!(a.cmp(&b), Ordering::Equal);
+        assert_eq!(HashSet::from([a.clone(), b.clone()]).len(), 1);
+
+        let mut versions: BTreeMap<GlobalFormId, Vec<&str>> = BTreeMap::new();
+        versions.entry(a).or_default().push("Upper.esp");
+        versions.entry(b).or_default().push("Lower.esp");
+        assert_eq!(versions.len(), 1);
+        // The first spelling seen is the one kept for display.
+        let (form_id, plugins) = versions.iter().next().unwrap();
+        assert_eq!(form_id.to_string(), "Skyrim.esm:000100");
+        assert_eq!(plugins, &["Upper.esp", "Lower.esp"]);
+    }
+
+    #[test]
+    fn different_object_ids_differ() {
+        let a = GlobalFormId::new("Skyrim.esm", 0x100);
+        assert_ne!(a, GlobalFormId::new("SKYRIM.ESM", 0x101));
+        assert_ne!(a, GlobalFormId::new("Update.esm", 0x100));
+        assert!(a < GlobalFormId::new("skyrim.esm", 0x101));
+        assert!(GlobalFormId::new("a.esp", 0xFFF) < GlobalFormId::new("B.esp", 0));
+    }
+
+    #[test]
+    fn parses_what_display_writes() {
+        let form_id = GlobalFormId::new("Dawnguard.esm", 0x00_1AB2);
+        assert_eq!(form_id.to_string().parse::<GlobalFormId>(), Ok(form_id));
+        assert!("Skyrim.esm".parse::<GlobalFormId>().is_err());
+        assert!(":000100".parse::<GlobalFormId>().is_err());
+        assert!("Skyrim.esm:1000000".parse::<GlobalFormId>().is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use byteorder::{LittleEndian, ReadBytesExt};
+use log::{debug, warn};
 use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
@@ -8,6 +9,8 @@ use std::io::Read;
 use std::path::Path;
 use std::str::from_utf8;
 
+mod form_id;
+pub use form_id::*;
 mod plugin;
 pub use plugin::*;
 pub mod records;
@@ -15,15 +18,15 @@ use records::*;
 pub mod plugin_writer;
 
 /// Read zstrings given a length
-fn read_zstring(reader: &mut dyn Read, len: u32) -> String {
+fn read_zstring(reader: &mut dyn Read, len: u32) -> Result<String, Error> {
     let mut buf: Vec<u8> = vec![];
     if len > 0 {
         // `(len - 1)` then skip the next byte because we want to exlude the \0 from our string
-        let _ = reader.take((len - 1) as u64).read_to_end(&mut buf);
-        let _ = reader.read(&mut [0; 1]); // Skip the \0
+        reader.take((len - 1) as u64).read_to_end(&mut buf)?;
+        reader.read_exact(&mut [0; 1])?; // Skip the \0
     } else {
         loop {
-            let c = reader.read_u8().unwrap();
+            let c = reader.read_u8()?;
             if c == 0 {
                 break;
             } else {
@@ -31,97 +34,189 @@ fn read_zstring(reader: &mut dyn Read, len: u32) -> String {
             }
         }
     }
-    String::from_utf8(buf).expect("Invalid UTF8")
+    String::from_utf8(buf).map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid UTF8"))
 }
 
 /// Read record identifiers
-fn read_ident(reader: &mut dyn Read) -> String {
+fn read_ident(reader: &mut dyn Read) -> Result<String, Error> {
     let mut buf: [u8; 4] = [0; 4];
-    let _ = reader.read_exact(&mut buf).unwrap();
+    reader.read_exact(&mut buf)?;
     //println!("{:?}", buf);
-    String::from(from_utf8(&buf).unwrap())
+    match from_utf8(&buf) {
+        Ok(ident) => Ok(String::from(ident)),
+        Err(_) => Err(Error::new(ErrorKind::InvalidData, "Invalid record type")),
+    }
 }
 
-fn read_u8(reader: &mut dyn Read) -> u8 {
-    reader.read_u8().unwrap()
-}
-fn read_u16(reader: &mut dyn Read) -> u16 {
-    reader.read_u16::<LittleEndian>().unwrap()
-}
-fn read_i32(reader: &mut dyn Read) -> i32 {
-    reader.read_i32::<LittleEndian>().unwrap()
+fn read_u16(reader: &mut dyn Read) -> Result<u16, Error> {
+    reader.read_u16::<LittleEndian>()
 }
-fn read_u32(reader: &mut dyn Read) -> u32 {
-    reader.read_u32::<LittleEndian>().unwrap()
+fn read_i32(reader: &mut dyn Read) -> Result<i32, Error> {
+    reader.read_i32::<LittleEndian>()
 }
-fn read_f32(reader: &mut dyn Read) -> f32 {
-    reader.read_f32::<LittleEndian>().unwrap()
+fn read_u32(reader: &mut dyn Read) -> Result<u32, Error> {
+    reader.read_u32::<LittleEndian>()
 }
-fn read_u64(reader: &mut dyn Read) -> u64 {
-    reader.read_u64::<LittleEndian>().unwrap()
+fn read_f32(reader: &mut dyn Read) -> Result<f32, Error> {
+    reader.read_f32::<LittleEndian>()
 }
-fn skip(reader: &mut dyn Read, len: u64) {
+fn skip(reader: &mut dyn Read, len: u64) -> Result<(), Error> {
     /*reader.fill_buf();
     reader.consume(len);*/
     //reader.seek(SeekFrom::Current(len as i64));
     let mut _trash = vec![];
-    reader.take(len).read_to_end(&mut _trash).unwrap();
+    if reader.take(len).read_to_end(&mut _trash)? as u64 != len {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "Unexpected end of file"));
+    }
     //println!("\tSkipped {} bytes", reader.take(len).read_to_end(&mut _trash).unwrap());
+    Ok(())
+}
+
+/// Read the subrecords of a record's data, joining XXXX lengths onto the
+/// subrecords they are for.
+fn parse_subrecords(mut data: &[u8]) -> Result<Vec<Subrecord>, Error> {
+    let mut subrecords = vec![];
+    let mut x_len: Option<u32> = None;
+    while !data.is_empty() {
+        let kind = read_ident(&mut data)?;
+        let mut len = read_u16(&mut data)? as u32;
+        if let Some(x_len) = x_len.take() {
+            len = x_len;
+        }
+        if kind == "XXXX" {
+            x_len = Some(read_u32(&mut data)?);
+            continue;
+        }
+        let mut buf = vec![];
+        if (&mut data).take(len as u64).read_to_end(&mut buf)? as u32 != len {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Unexpected end of record",
+            ));
+        }
+        subrecords.push(Subrecord { kind, data: buf });
+    }
+    Ok(subrecords)
 }
 
-fn parse_record_header(mut reader: &mut dyn Read) -> RecordHeader {
-    RecordHeader {
-        record_type: read_ident(&mut reader),
-        size: read_u32(&mut reader),
-        flags: read_u32(&mut reader),
-        id: read_u32(&mut reader),
-        revision: read_u32(&mut reader),
-        version: read_u16(&mut reader),
-        unknown: read_u16(&mut reader),
+/// TES4 record flag marking the record's data as zlib compressed.
+const COMPRESSED_FLAG: u32 = 0x0004_0000;
+
+/// Read the subrecords of the record `header` is for. Compressed records
+/// are skipped, giving `None`.
+fn read_record(
+    reader: &mut dyn Read,
+    header: &RecordHeader,
+    plugin_name: &str,
+) -> Result<Option<Vec<Subrecord>>, Error> {
+    if header.flags & COMPRESSED_FLAG != 0 {
+        warn!(
+            "{}: skipping compressed {} record {:08X}",
+            plugin_name, header.record_type, header.id
+        );
+        skip(reader, header.size as u64)?;
+        return Ok(None);
     }
+    let mut data = vec![];
+    if reader.take(header.size as u64).read_to_end(&mut data)? as u32 != header.size {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "Unexpected end of file",
+        ));
+    }
+    parse_subrecords(&data).map(Some)
 }
 
+fn parse_record_header(mut reader: &mut dyn Read) -> Result<RecordHeader, Error> {
+    Ok(RecordHeader {
+        record_type: read_ident(&mut reader)?,
+        size: read_u32(&mut reader)?,
+        flags: read_u32(&mut reader)?,
+        id: read_u32(&mut reader)?,
+        revision: read_u32(&mut reader)?,
+        version: read_u16(&mut reader)?,
+        unknown: read_u16(&mut reader)?,
+    })
+}
+
+/// HEDR versions we know how to read.
+/// 0.94 is Skyrim and Fallout 3, 0.95 and 1.0 are Fallout 4 before and
+/// after its light plugin update, 1.32 to 1.34 are Fallout New Vegas and
+/// 1.7/1.71 are Skyrim Special Edition. Oblivion also saves 1.0, but its
+/// shorter record headers are turned away before the version is read.
+const KNOWN_VERSIONS: [f32; 8] = [0.94, 0.95, 1.0, 1.32, 1.33, 1.34, 1.7, 1.71];
+
 pub fn parse(p: &Path) -> Result<Plugin, Error> {
-    println!("+Parsing `{}`", p.file_name().unwrap().to_str().unwrap());
+    debug!("Parsing `{}`", p.display());
+    parse_plugin(p, false)
+}
+
+/// Parse only the TES4 header of a plugin, which is enough to know its
+/// masters and flags without reading through every record.
+pub fn parse_header(p: &Path) -> Result<Plugin, Error> {
+    parse_plugin(p, true)
+}
+
+fn parse_plugin(p: &Path, header_only: bool) -> Result<Plugin, Error> {
     let mut plugin = Plugin::new(p);
-    let file: File = File::open(p).expect("Unable to open file!");
+    let file: File = File::open(p)?;
     let mut reader = BufReader::with_capacity(256, file);
 
     // Magic bytes
-    if read_ident(&mut reader) != "TES4" {
+    let tes4 = parse_record_header(&mut reader)?;
+    if tes4.record_type == "TES3" {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Morrowind plugins aren't supported",
+        ));
+    }
+    // Oblivion's record headers are 20 bytes rather than 24, which leaves
+    // the start of HEDR where the version should be.
+    if tes4.version == u16::from_le_bytes(*b"HE") && tes4.unknown == u16::from_le_bytes(*b"DR") {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Oblivion plugins aren't supported",
+        ));
+    }
+    if tes4.record_type != "TES4" {
         return Result::Err(Error::new(
             ErrorKind::InvalidData,
             String::from("Not a valid ESM/ESP file"),
         ));
     }
-    // skip the random junk between TES4 and HEDR
-    skip(&mut reader, 20);
+    plugin.flags = tes4.flags;
 
     // Extended field size
     let mut xxxx = false;
     let mut x_len: u32 = 0;
 
     // Header
-    let mut ident = read_ident(&mut reader);
-    assert!(ident.as_str() == "HEDR");
-    let hedr_size = read_u16(&mut reader);
+    let mut ident = read_ident(&mut reader)?;
+    if ident.as_str() != "HEDR" {
+        return Err(Error::new(ErrorKind::InvalidData, "Expected HEDR"));
+    }
+    let hedr_size = read_u16(&mut reader)?;
     if hedr_size != 12 {
-        println!(
-            "HEDR has unusual size field ({}). Ignoring. If you get weird errors,\
+        warn!(
+            "{}: HEDR has unusual size field ({}). Ignoring. If you get weird errors, \
             please verify that the file is not corrupt and submit a bug report with \
             your file.",
+            plugin.name,
             hedr_size
         );
     }
-    plugin.version = read_f32(&mut reader);
-    plugin.num_records = read_i32(&mut reader);
-    plugin.next_object_id = read_u32(&mut reader);
-    if plugin.version != 1.7 && plugin.version != 0.94 {
-        panic!("{}", format!("Incorrect version!: {}", plugin.version));
+    plugin.version = read_f32(&mut reader)?;
+    plugin.num_records = read_i32(&mut reader)?;
+    plugin.next_object_id = read_u32(&mut reader)?;
+    if !KNOWN_VERSIONS.contains(&plugin.version) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Incorrect version!: {}", plugin.version),
+        ));
     }
-    ident = read_ident(&mut reader);
+    ident = read_ident(&mut reader)?;
     loop {
-        let mut len: u32 = read_u16(&mut reader) as u32;
+        let mut len: u32 = read_u16(&mut reader)? as u32;
         if xxxx {
             len = x_len;
             //println!("\tXXXX: {}", x_len);
@@ -129,41 +224,44 @@ pub fn parse(p: &Path) -> Result<Plugin, Error> {
         }
         match ident.as_str() {
             "MAST" => {
-                let name = read_zstring(&mut reader, len);
+                let name = read_zstring(&mut reader, len)?;
                 //println!("\tMAST: `{}`", &name);
                 plugin.masters.push(name);
-                skip(&mut reader, 14);
+                skip(&mut reader, 14)?;
             }
             "XXXX" => {
                 xxxx = true;
-                x_len = read_u32(&mut reader);
+                x_len = read_u32(&mut reader)?;
             }
-            "CNAM" => plugin.author = read_zstring(&mut reader, len),
-            "SNAM" => plugin.description = read_zstring(&mut reader, len),
-            "INTV" => plugin.intv = read_u32(&mut reader),
+            "CNAM" => plugin.author = read_zstring(&mut reader, len)?,
+            "SNAM" => plugin.description = read_zstring(&mut reader, len)?,
+            "INTV" => plugin.intv = read_u32(&mut reader)?,
             "ONAM" => {
                 //println!("\tOverrides len: {}", len);
                 for _ in 0..len / 4 {
-                    plugin.overrides.push(read_u32(&mut reader));
+                    plugin.overrides.push(read_u32(&mut reader)?);
                 }
-                //skip(&mut reader, len as u64); // NYI
+                //skip(&mut reader, len as u64)?; // NYI
             }
             "INCC" => {
                 // Unknown
-                read_u32(&mut reader);
+                read_u32(&mut reader)?;
             }
             _ => {
-                // Invalid data. Time to panic.
-                panic!("{}", format!("\tUnknown: `{}`", ident));
+                // Invalid data.
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown header field `{}`", ident),
+                ));
             }
         }
         // Nothing left to read. A plugin can
         // be just the header, which will just
         // cause the .bsa file for it to load.
-        if reader.fill_buf().unwrap().len() == 0 {
+        if reader.fill_buf()?.is_empty() {
             break;
         }
-        ident = read_ident(&mut reader);
+        ident = read_ident(&mut reader)?;
         if ident.as_str() == "GRUP" {
             // End of the header. Moving on.
             break;
@@ -171,78 +269,86 @@ pub fn parse(p: &Path) -> Result<Plugin, Error> {
     }
 
     //println!("\tFinished parsing header");
+    if header_only {
+        return Ok(plugin);
+    }
     while ident.as_str() == "GRUP" {
         // Group length includes header size: 24 bytes
-        let group_len = read_u32(&mut reader) - 24;
+        let group_len = read_u32(&mut reader)? - 24;
         let mut label = [0; 4];
-        let _ = reader.read_exact(&mut label).unwrap();
-        let label_str;
-        let group_type = read_i32(&mut reader);
-        let _stamp = read_u16(&mut reader);
-        let _unknown = read_u16(&mut reader);
-        let _version = read_u16(&mut reader);
-        let _unknown2 = read_u16(&mut reader);
+        reader.read_exact(&mut label)?;
+        let group_type = read_i32(&mut reader)?;
+        let _stamp = read_u16(&mut reader)?;
+        let _unknown = read_u16(&mut reader)?;
+        let _version = read_u16(&mut reader)?;
+        let _unknown2 = read_u16(&mut reader)?;
         if group_type != 0 {
             return Err(Error::new(
                 ErrorKind::InvalidData,
                 "Expected top level group",
             ));
         }
-        label_str = from_utf8(&label).unwrap();
+        let label_str = from_utf8(&label).unwrap();
         //println!("Found GRUP<{}>[{}]", group_type, group_len);
-        if label_str == "MUSC" {
+        if label_str == "MUSC" || label_str == "MUST" {
             let mut pos = 0;
             while pos < group_len {
-                let record_header = parse_record_header(&mut reader);
-                if read_ident(&mut reader) != "EDID" {
-                    return Err(Error::new(ErrorKind::InvalidData, "Expected EDID"));
-                }
-                let edid_len = read_u16(&mut reader) as u32;
-                let editor_id = read_zstring(&mut reader, edid_len);
-                if read_ident(&mut reader) != "FNAM" {
-                    return Err(Error::new(ErrorKind::InvalidData, "Expected FNAM"));
+                let record_header = parse_record_header(&mut reader)?;
+                pos += 24 + record_header.size;
+                let subrecords = match read_record(&mut reader, &record_header, &plugin.name)? {
+                    Some(subrecords) => subrecords,
+                    None => continue,
+                };
+                let editor_id = match subrecords.iter().find(|s| s.kind == "EDID") {
+                    Some(edid) => read_zstring(&mut edid.data.as_slice(), edid.data.len() as u32)?,
+                    None => String::new(),
+                };
+                if label_str == "MUST" {
+                    plugin.tracks.push(MUST {
+                        form_id: record_header.id,
+                        editor_id,
+                        subrecords,
+                    });
+                    continue;
                 }
-                read_u16(&mut reader);
-                let flags = read_u32(&mut reader);
-                if read_ident(&mut reader) != "PNAM" {
-                    return Err(Error::new(ErrorKind::InvalidData, "Expected PNAM"));
-                }
-                read_u16(&mut reader);
-                let priority = read_u16(&mut reader);
-                let ducking = read_u16(&mut reader);
-                if read_ident(&mut reader) != "WNAM" {
-                    return Err(Error::new(ErrorKind::InvalidData, "Expected WNAM"));
-                }
-                read_u16(&mut reader);
-                let fade_duration = read_f32(&mut reader);
-                if read_ident(&mut reader) != "TNAM" {
-                    return Err(Error::new(ErrorKind::InvalidData, "Expected TNAM"));
-                }
-                let data_len = read_u16(&mut reader) as u32;
-                let mut track_ids: Vec<u32> = vec![];
-                for _ in 0..data_len / 4 {
-                    track_ids.push(read_u32(&mut reader));
-                }
-                pos += 67 + editor_id.as_bytes().len() as u32 + track_ids.len() as u32 * 4;
-                plugin.music.push(MUSC {
+                // Every field is optional. Emptied music types have no TNAM.
+                let mut musc = MUSC {
                     form_id: record_header.id,
-                    editor_id: editor_id,
-                    flags: flags,
-                    priority: priority,
-                    ducking: ducking,
-                    fade_duration: fade_duration,
-                    track_ids: track_ids,
-                });
+                    editor_id,
+                    flags: 0,
+                    priority: 0,
+                    ducking: 0,
+                    fade_duration: 0.0,
+                    track_ids: vec![],
+                };
+                for subrecord in subrecords.iter() {
+                    let mut data = subrecord.data.as_slice();
+                    match subrecord.kind.as_str() {
+                        "FNAM" => musc.flags = read_u32(&mut data)?,
+                        "PNAM" => {
+                            musc.priority = read_u16(&mut data)?;
+                            musc.ducking = read_u16(&mut data)?;
+                        }
+                        "WNAM" => musc.fade_duration = read_f32(&mut data)?,
+                        "TNAM" => {
+                            for _ in 0..data.len() / 4 {
+                                musc.track_ids.push(read_u32(&mut data)?);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                plugin.music.push(musc);
             }
         } else {
             // We don't care about whatever this is.
-            skip(&mut reader, group_len as u64);
+            skip(&mut reader, group_len as u64)?;
         }
         // EOF
-        if reader.fill_buf().unwrap().len() == 0 {
+        if reader.fill_buf()?.is_empty() {
             break;
         }
-        ident = read_ident(&mut reader);
+        ident = read_ident(&mut reader)?;
     }
     //println!("\tAuthor: {}\n\tDescription: {}", plugin.author, plugin.description);
     /*for musc in plugin.music.iter() {
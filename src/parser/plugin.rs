@@ -2,11 +2,13 @@ use std::boxed::Box;
 use std::path::Path;
 use std::path::PathBuf;
 
-use crate::parser::MUSC;
+use crate::parser::GlobalFormId;
+use crate::parser::{MUSC, MUST};
 
 pub struct Plugin {
     path: Box<PathBuf>,
     pub name: String,
+    pub flags: u32,
     pub version: f32,
     pub num_records: i32,
     pub next_object_id: u32,
@@ -17,13 +19,30 @@ pub struct Plugin {
     pub intv: u32, // unknown
     pub incc: u32, // unknown
     pub music: Vec<MUSC>,
+    pub tracks: Vec<MUST>,
 }
 
 impl Plugin {
+    /// TES4 record flag marking the plugin as a master.
+    pub const MASTER_FLAG: u32 = 0x0000_0001;
+    /// TES4 record flag marking the plugin's strings as stored in external
+    /// .STRINGS files rather than in the records themselves.
+    pub const LOCALIZED_FLAG: u32 = 0x0000_0080;
+    /// TES4 record flag marking the plugin as light (ESL). Only Skyrim
+    /// Special Edition and Fallout 4 understand it.
+    pub const LIGHT_FLAG: u32 = 0x0000_0200;
+    /// Most masters a plugin can have, as the master index is a byte and
+    /// the last index is the plugin's own.
+    pub const MAX_MASTERS: u8 = 255;
+
     pub fn new(p: &Path) -> Plugin {
         Plugin {
             path: Box::new(p.to_owned()),
-            name: String::from(p.file_name().unwrap().to_str().unwrap()),
+            name: p
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            flags: 0,
             num_records: 0,
             next_object_id: 0,
             author: String::from(""),
@@ -34,9 +53,107 @@ impl Plugin {
             incc: 0,
             version: 0.0,
             music: vec![],
+            tracks: vec![],
         }
     }
     pub fn path(&self) -> &Path {
         self.path.as_path()
     }
+
+    fn has_extension(&self, ext: &str) -> bool {
+        self.path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| e.eq_ignore_ascii_case(ext))
+    }
+
+    /// Masters load before every other plugin. `.esm` and `.esl` files
+    /// are treated as masters whether or not they have the flag set.
+    pub fn is_master(&self) -> bool {
+        self.flags & Self::MASTER_FLAG != 0
+            || self.has_extension("esm")
+            || self.has_extension("esl")
+    }
+
+    /// Light plugins are loaded into the shared `FE` index, with
+    /// only 12 bits of FormID space each. `.esl` files are always light.
+    pub fn is_light(&self) -> bool {
+        self.flags & Self::LIGHT_FLAG != 0 || self.has_extension("esl")
+    }
+
+    pub fn is_localized(&self) -> bool {
+        self.flags & Self::LOCALIZED_FLAG != 0
+    }
+
+    /// The FormID `id` would have in this plugin, if it can refer to it.
+    pub fn local_form_id(&self, id: &GlobalFormId) -> Option<u32> {
+        let index = if id.plugin.eq_ignore_ascii_case(&self.name) {
+            self.masters.len()
+        } else {
+            self.masters
+                .iter()
+                .position(|m| m.eq_ignore_ascii_case(&id.plugin))?
+        };
+        Some(((index as u32) << 24) | id.object_id)
+    }
+
+    /// Resolve a FormID read from this plugin to the plugin defining it.
+    pub fn resolve_form_id(&self, form_id: u32) -> GlobalFormId {
+        let index = (form_id >> 24) as usize;
+        match self.masters.get(index) {
+            Some(master) => GlobalFormId::new(master.as_str(), form_id),
+            // Light plugins only have 12 bits of their own FormID space.
+            None if self.is_light() => GlobalFormId::new(self.name.as_str(), form_id & 0xFFF),
+            None => GlobalFormId::new(self.name.as_str(), form_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin(name: &str, flags: u32) -> Plugin {
+        let mut plugin = Plugin::new(Path::new(name));
+        plugin.flags = flags;
+        plugin.masters = vec![String::from("Skyrim.esm"), String::from("Update.esm")];
+        plugin
+    }
+
+    #[test]
+    fn resolves_form_ids_against_masters() {
+        let plugin = plugin("Full.esp", 0);
+        assert_eq!(
+            plugin.resolve_form_id(0x0000_0100),
+            GlobalFormId::new("Skyrim.esm", 0x100)
+        );
+        assert_eq!(
+            plugin.resolve_form_id(0x0100_0200),
+            GlobalFormId::new("Update.esm", 0x200)
+        );
+        assert_eq!(
+            plugin.resolve_form_id(0x0201_2345),
+            GlobalFormId::new("Full.esp", 0x01_2345)
+        );
+    }
+
+    #[test]
+    fn masks_light_plugins_own_form_ids_to_12_bits() {
+        for light in [
+            plugin("Flagged.esp", Plugin::LIGHT_FLAG),
+            plugin("Light.esl", 0),
+        ] {
+            assert!(light.is_light());
+            // The upper bits of a light plugin's own FormIDs are ignored.
+            assert_eq!(
+                light.resolve_form_id(0x0201_2ABC),
+                GlobalFormId::new(light.name.as_str(), 0xABC)
+            );
+            // While records in its masters keep all 24.
+            assert_eq!(
+                light.resolve_form_id(0x0001_2ABC),
+                GlobalFormId::new("Skyrim.esm", 0x01_2ABC)
+            );
+        }
+    }
 }
@@ -1,11 +1,56 @@
 use byteorder::{LittleEndian, WriteBytesExt};
+use log::debug;
 use std::io::Error;
+use std::io::ErrorKind;
 use std::io::Write;
 
+use crate::Game;
 use crate::Plugin;
 
+/// Options controlling how a plugin is written.
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    /// Set the light (ESL) flag on the TES4 header, regardless of the
+    /// plugin's own flags. Light plugins don't take up one of the 254
+    /// full plugin slots, but can only define 2048 new records.
+    pub light: bool,
+    /// Form version written to the header of every record.
+    pub form_version: u16,
+}
+
+impl WriteOptions {
+    /// Light output is the default for every game that supports it.
+    pub fn for_game(game: Game) -> Self {
+        WriteOptions {
+            light: game.supports_light_plugins(),
+            form_version: game.form_version(),
+        }
+    }
+}
+
+/// Make sure every record the plugin defines itself, rather than
+/// overrides, fits in the FormID range available to light plugins.
+/// HEDR version 1.71 extended the range down to 0x000.
+fn validate_light_form_ids(plugin: &Plugin) -> Result<(), Error> {
+    let min_object_id = if plugin.version >= 1.71 { 0x000 } else { 0x800 };
+    for musc in plugin.music.iter() {
+        let index = (musc.form_id >> 24) as usize;
+        let object_id = musc.form_id & 0x00FF_FFFF;
+        if index >= plugin.masters.len() && !(min_object_id..=0xFFF).contains(&object_id) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "`{}` ({:08X}) is outside of the FormID range of a light plugin",
+                    musc.editor_id, musc.form_id
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
 fn write_ident(writer: &mut dyn Write, ident: &str) {
-    writer.write(ident.as_bytes()).unwrap();
+    writer.write_all(ident.as_bytes()).unwrap();
 }
 
 fn write_zstring(writer: &mut dyn Write, xxxx: bool, zstring: &str) {
@@ -17,7 +62,7 @@ fn write_zstring(writer: &mut dyn Write, xxxx: bool, zstring: &str) {
             .write_u16::<LittleEndian>(bytes.len() as u16 + 1)
             .unwrap();
     }
-    writer.write(bytes).unwrap();
+    writer.write_all(bytes).unwrap();
     writer.write_u8(0).unwrap(); // Null terminated.
 }
 
@@ -41,67 +86,72 @@ fn write_u64(writer: &mut dyn Write, v: u64) {
     writer.write_u64::<LittleEndian>(v).unwrap();
 }
 
-pub fn write_plugin(mut writer: &mut dyn Write, plugin: &Plugin) -> Result<(), Error> {
-    write_ident(&mut writer, "TES4");
-    // size of the TES4 header // TODO: XXXX support
-    write_u16(
-        &mut writer,
-        // "HEDR" + len + fields
-        18
-        // "CNAM" + len + str len + null
-        + 7 + plugin.author.as_bytes().len() as u16
-        // "SNAM" + len + str len + null
-        + 7 + plugin.description.as_bytes().len() as u16
-        // each "MAST" + len + str len + null  + "DATA" + len + u64
-        + plugin.masters.iter().fold(0, |i, master| i + master.as_bytes().len() as u16 + 21), // "ONAM" + len + each u32
-                                                                                              /*+ 6 + plugin.overrides.len() as u16 * 4*/
-    );
-    // No idea what this is about, as there's no documentation, but every esp file has it.
-    let buf: [u8; 18] = [
-        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2C,
-        0x00, 0x00, 0x00,
-    ];
-    let _ = writer.write(&buf);
-    write_ident(&mut writer, "HEDR");
-    write_u16(&mut writer, 12);
-    write_f32(&mut writer, plugin.version);
-    write_i32(&mut writer, plugin.num_records);
-    write_u32(&mut writer, plugin.next_object_id);
-    write_ident(&mut writer, "CNAM");
-    write_zstring(&mut writer, false, plugin.author.as_str());
-    write_ident(&mut writer, "SNAM");
-    write_zstring(&mut writer, false, plugin.description.as_str());
+pub fn write_plugin(
+    mut writer: &mut dyn Write,
+    plugin: &Plugin,
+    options: &WriteOptions,
+) -> Result<(), Error> {
+    let mut flags = plugin.flags;
+    if options.light {
+        validate_light_form_ids(plugin)?;
+        flags |= Plugin::LIGHT_FLAG;
+    }
+    // Write the header's subrecords first, so its size is whatever was written.
+    let mut header: Vec<u8> = vec![];
+    write_ident(&mut header, "HEDR");
+    write_u16(&mut header, 12);
+    write_f32(&mut header, plugin.version);
+    write_i32(&mut header, plugin.num_records);
+    write_u32(&mut header, plugin.next_object_id);
+    write_ident(&mut header, "CNAM");
+    write_zstring(&mut header, false, plugin.author.as_str());
+    write_ident(&mut header, "SNAM");
+    write_zstring(&mut header, false, plugin.description.as_str());
     for master in plugin.masters.iter() {
-        write_ident(&mut writer, "MAST");
-        write_zstring(&mut writer, false, master);
-        write_ident(&mut writer, "DATA");
-        write_u16(&mut writer, 8); // Size of data field.
-        write_u64(&mut writer, 0); // Not even used.
+        write_ident(&mut header, "MAST");
+        write_zstring(&mut header, false, master);
+        write_ident(&mut header, "DATA");
+        write_u16(&mut header, 8); // Size of data field.
+        write_u64(&mut header, 0); // Not even used.
     }
-    if plugin.overrides.len() > 0 {
-        println!("Overrides? Ugh");
+    if !plugin.overrides.is_empty() {
+        debug!(
+            "{}: writing {} overridden records to ONAM",
+            plugin.name,
+            plugin.overrides.len()
+        );
         let onam_bytes: u32 = plugin.overrides.len() as u32 * 4;
-        let xxxx_onam = plugin.overrides.len() as u32 > onam_bytes;
+        let xxxx_onam = onam_bytes > u16::MAX as u32;
         if xxxx_onam {
-            write_ident(&mut writer, "XXXX");
-            write_u32(&mut writer, onam_bytes);
+            write_ident(&mut header, "XXXX");
+            write_u16(&mut header, 4);
+            write_u32(&mut header, onam_bytes);
         }
-        write_ident(&mut writer, "ONAM");
+        write_ident(&mut header, "ONAM");
         if !xxxx_onam {
-            write_u16(&mut writer, onam_bytes as u16);
+            write_u16(&mut header, onam_bytes as u16);
         } else {
-            write_u16(&mut writer, 0);
+            write_u16(&mut header, 0);
         }
         for form_id in plugin.overrides.iter() {
-            write_u32(&mut writer, *form_id);
+            write_u32(&mut header, *form_id);
         }
     }
-    if plugin.music.len() > 0 {
+
+    write_ident(&mut writer, "TES4");
+    write_u32(&mut writer, header.len() as u32);
+    write_u32(&mut writer, flags);
+    write_u32(&mut writer, 0); // form id
+    write_u32(&mut writer, 0); // revision
+    write_u16(&mut writer, options.form_version);
+    write_u16(&mut writer, 0); // unknown
+    writer.write_all(&header)?;
+    if !plugin.music.is_empty() {
         //println!("Writing Music");
         write_ident(&mut writer, "GRUP");
         // get size of all MUSC values contained
         let data_size: u32 = plugin.music.iter().fold(0, |s, musc| {
-            s + 67 + musc.editor_id.as_bytes().len() as u32 + musc.track_ids.len() as u32 * 4
+            s + 67 + musc.editor_id.len() as u32 + musc.track_ids.len() as u32 * 4
         });
         //println!("GRUP size: {}", data_size + 24);
         write_u32(&mut writer, data_size + 24); // size (including header size)
@@ -115,12 +165,12 @@ pub fn write_plugin(mut writer: &mut dyn Write, plugin: &Plugin) -> Result<(), E
             write_ident(&mut writer, "MUSC");
             write_u32(
                 &mut writer,
-                43 + musc.editor_id.as_bytes().len() as u32 + musc.track_ids.len() as u32 * 4,
+                43 + musc.editor_id.len() as u32 + musc.track_ids.len() as u32 * 4,
             );
             write_u32(&mut writer, 0x00000000); // flags
             write_u32(&mut writer, musc.form_id); // form id. Hope to god I don't have to do this the hard way
             write_u32(&mut writer, 0); // revision
-            write_u16(&mut writer, 0); // version
+            write_u16(&mut writer, options.form_version); // version
             write_u16(&mut writer, 0); // unknown
 
             write_ident(&mut writer, "EDID");
@@ -144,3 +194,79 @@ pub fn write_plugin(mut writer: &mut dyn Write, plugin: &Plugin) -> Result<(), E
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::records::MUSC;
+    use crate::test_util::TempDir;
+
+    fn patch(form_id: u32) -> Plugin {
+        let mut plugin = Plugin::new(Path::new("patch.esp"));
+        plugin.version = 1.7;
+        plugin.author = String::from("Author");
+        plugin.description = String::from("Description");
+        plugin.masters = vec![String::from("Skyrim.esm"), String::from("Tracks.esp")];
+        plugin.num_records = 2;
+        plugin.music.push(MUSC {
+            form_id,
+            editor_id: String::from("MUSCombatBoss"),
+            flags: 0x01,
+            priority: 60,
+            ducking: 0x0100,
+            fade_duration: 2.5,
+            track_ids: vec![],
+        });
+        plugin
+    }
+
+    #[test]
+    fn parses_what_it_writes() {
+        let options = WriteOptions::for_game(Game::SkyrimSE);
+        let mut buf: Vec<u8> = vec![];
+        write_plugin(&mut buf, &patch(0x0000_0100), &options).unwrap();
+        // The header's form version, and its size up to the MUSC group.
+        assert_eq!(u16::from_le_bytes([buf[20], buf[21]]), 44);
+        let header_size = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+        assert_eq!(&buf[24 + header_size..28 + header_size], b"GRUP");
+
+        let dir = TempDir::new();
+        let plugin = crate::parse(&dir.write("patch.esp", &buf)).unwrap();
+        assert_eq!(plugin.flags, Plugin::LIGHT_FLAG);
+        assert_eq!(plugin.version, 1.7);
+        assert_eq!(plugin.author, "Author");
+        assert_eq!(plugin.description, "Description");
+        assert_eq!(plugin.masters, ["Skyrim.esm", "Tracks.esp"]);
+        assert_eq!(plugin.music.len(), 1);
+        let musc = &plugin.music[0];
+        assert_eq!(musc.form_id, 0x0000_0100);
+        assert_eq!(musc.editor_id, "MUSCombatBoss");
+        assert_eq!(musc.flags, 0x01);
+        assert_eq!(musc.priority, 60);
+        assert_eq!(musc.ducking, 0x0100);
+        assert_eq!(musc.fade_duration, 2.5);
+        assert!(musc.track_ids.is_empty());
+    }
+
+    #[test]
+    fn light_plugins_only_define_records_up_to_0xfff() {
+        let light = WriteOptions {
+            light: true,
+            form_version: 44,
+        };
+        // Index 02 is the plugin itself, after its two masters.
+        assert!(write_plugin(&mut vec![], &patch(0x0200_0FFF), &light).is_ok());
+        let err = write_plugin(&mut vec![], &patch(0x0200_1000), &light).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        // Overrides of the masters' records can use any FormID.
+        assert!(write_plugin(&mut vec![], &patch(0x0100_1000), &light).is_ok());
+        // As can full plugins.
+        let full = WriteOptions {
+            light: false,
+            ..light
+        };
+        assert!(write_plugin(&mut vec![], &patch(0x0200_1000), &full).is_ok());
+    }
+}
@@ -9,6 +9,22 @@ pub struct MUSC {
     pub track_ids: Vec<u32>,
 }
 
+/// A music track. Only the editor ID is read out, with every subrecord
+/// kept as it is.
+#[derive(Debug, Clone)]
+pub struct MUST {
+    pub form_id: u32,
+    pub editor_id: String,
+    /// Every subrecord, including the EDID, in the record's order.
+    pub subrecords: Vec<Subrecord>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subrecord {
+    pub kind: String,
+    pub data: Vec<u8>,
+}
+
 #[derive(Debug, Clone)]
 pub struct RecordHeader {
     pub record_type: String,
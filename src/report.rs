@@ -0,0 +1,208 @@
+//! A machine-readable account of a merge, for tools that check mod lists.
+//!
+//! The report serializes to JSON with the field names used here. FormIDs
+//! are written as `Plugin.esp:XXXXXX`, with the object ID in hex. Fields
+//! will only be added within a `version`; renaming or removing one bumps it.
+
+use serde::Serialize;
+
+use crate::records::MUSC;
+use crate::{Game, GlobalFormId, LoadOrderEntry, Plugin};
+
+/// Version of the report format.
+pub const REPORT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    /// Always `REPORT_VERSION`.
+    pub version: u32,
+    /// Id of the game, such as `skyrimse`. Missing if no game was found.
+    pub game: Option<String>,
+    /// The merge strategy used, such as `three-way`.
+    pub strategy: Option<String>,
+    /// Every plugin in the load order, active or not, in load order.
+    pub load_order: Vec<ReportPlugin>,
+    /// The MUSC records of each plugin that has any, in load order.
+    pub plugins: Vec<PluginMusic>,
+    /// The records of the patch.
+    pub merged: Vec<MergedMusic>,
+    /// Records the config's rules left out of the patch.
+    pub skipped: Vec<SkippedMusic>,
+    /// Overrides identical to the record in the plugin defining it (ITMs),
+    /// which the merge ignores.
+    pub identical: Vec<IdenticalOverride>,
+    /// The first patch, if the merge got far enough to decide on one.
+    pub output: Option<ReportOutput>,
+    /// Every patch, in order. There's more than one when the records need
+    /// more masters than one plugin can have.
+    pub outputs: Vec<ReportOutput>,
+    /// Problems that didn't stop the merge, such as missing plugins.
+    pub warnings: Vec<String>,
+    /// Problems that stopped a plugin from being merged, or the merge itself.
+    pub errors: Vec<String>,
+}
+
+impl Report {
+    pub fn new(game: Option<Game>) -> Self {
+        Report {
+            version: REPORT_VERSION,
+            game: game.map(|game| String::from(game.id())),
+            strategy: None,
+            load_order: vec![],
+            plugins: vec![],
+            merged: vec![],
+            skipped: vec![],
+            identical: vec![],
+            output: None,
+            outputs: vec![],
+            warnings: vec![],
+            errors: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportPlugin {
+    pub name: String,
+    pub active: bool,
+    pub master: bool,
+    pub light: bool,
+}
+
+impl From<&LoadOrderEntry> for ReportPlugin {
+    fn from(entry: &LoadOrderEntry) -> Self {
+        ReportPlugin {
+            name: entry.name.clone(),
+            active: entry.active,
+            master: entry.master,
+            light: entry.light,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PluginMusic {
+    pub plugin: String,
+    /// True if the config left the plugin out of the merge.
+    pub excluded: bool,
+    pub records: Vec<MusicRecord>,
+}
+
+impl From<&Plugin> for PluginMusic {
+    fn from(plugin: &Plugin) -> Self {
+        PluginMusic {
+            plugin: plugin.name.clone(),
+            excluded: false,
+            records: plugin
+                .music
+                .iter()
+                .map(|musc| MusicRecord::new(plugin, musc))
+                .collect(),
+        }
+    }
+}
+
+/// A MUSC record as one plugin has it.
+#[derive(Debug, Clone, Serialize)]
+pub struct MusicRecord {
+    pub form_id: GlobalFormId,
+    pub editor_id: String,
+    pub flags: u32,
+    pub priority: u16,
+    pub ducking: u16,
+    pub fade_duration: f32,
+    /// The record's MUST tracks, in the plugin's order.
+    pub tracks: Vec<GlobalFormId>,
+}
+
+impl MusicRecord {
+    pub fn new(plugin: &Plugin, musc: &MUSC) -> Self {
+        MusicRecord {
+            form_id: plugin.resolve_form_id(musc.form_id),
+            editor_id: musc.editor_id.clone(),
+            flags: musc.flags,
+            priority: musc.priority,
+            ducking: musc.ducking,
+            fade_duration: musc.fade_duration,
+            tracks: musc
+                .track_ids
+                .iter()
+                .map(|id| plugin.resolve_form_id(*id))
+                .collect(),
+        }
+    }
+}
+
+/// A record of the patch.
+#[derive(Debug, Clone, Serialize)]
+pub struct MergedMusic {
+    pub form_id: GlobalFormId,
+    pub editor_id: String,
+    /// The last plugin to override the record, whose version the game uses
+    /// without the patch. This can be an ITM or an excluded plugin's.
+    pub winner: String,
+    /// The plugin whose version of the record the overrides were compared
+    /// against, normally the one defining it.
+    pub base: String,
+    pub flags: u32,
+    pub priority: u16,
+    pub ducking: u16,
+    pub fade_duration: f32,
+    /// The merged track list, in the patch's order.
+    pub tracks: Vec<MergedTrack>,
+    /// Tracks of the base version that overrides removed.
+    pub removed: Vec<MergedTrack>,
+    /// Fields that overrides changed to different values. The patch takes
+    /// the last change.
+    pub conflicts: Vec<FieldConflict>,
+    /// Names of the config rules applied to the record, in order.
+    pub rules: Vec<String>,
+}
+
+/// A record a config rule left out of the patch.
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedMusic {
+    pub form_id: GlobalFormId,
+    pub editor_id: String,
+    /// Name of the rule that skipped it.
+    pub rule: String,
+}
+
+/// An override that changes nothing from the defining plugin's record.
+#[derive(Debug, Clone, Serialize)]
+pub struct IdenticalOverride {
+    pub form_id: GlobalFormId,
+    pub editor_id: String,
+    /// The plugin with the override.
+    pub plugin: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MergedTrack {
+    pub form_id: GlobalFormId,
+    /// The plugins whose version of the record lists the track or, for
+    /// removed tracks, leaves it out, in load order.
+    pub plugins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldConflict {
+    /// `flags`, `priority`, `ducking` or `fade_duration`.
+    pub field: String,
+    /// Each plugin that changed the field from the base, in load order.
+    pub changes: Vec<FieldChange>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldChange {
+    pub plugin: String,
+    pub value: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportOutput {
+    pub path: String,
+    pub masters: Vec<String>,
+    /// False for dry runs and failed writes.
+    pub written: bool,
+}
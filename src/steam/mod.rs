@@ -0,0 +1,282 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+use log::debug;
+
+use crate::Game;
+
+pub mod vdf;
+use vdf::Value;
+
+/// Directories Steam may be installed to.
+#[cfg(windows)]
+fn steam_root_candidates() -> Vec<PathBuf> {
+    let mut roots: Vec<PathBuf> = vec![];
+    if let Ok(path) = winreg::RegKey::predef(winreg::enums::HKEY_CURRENT_USER)
+        .open_subkey_with_flags("Software\\Valve\\Steam", winreg::enums::KEY_READ)
+        .and_then(|key| key.get_value::<String, _>("SteamPath"))
+    {
+        roots.push(PathBuf::from(path));
+    }
+    roots.push(PathBuf::from("C:\\Program Files (x86)\\Steam"));
+    roots
+}
+
+/// Directories Steam may be installed to. `~/.steam/steam` is a symlink
+/// to wherever the distribution's package put it, and Flatpak keeps its
+/// own copy in the app's sandbox.
+#[cfg(not(windows))]
+fn steam_root_candidates() -> Vec<PathBuf> {
+    match std::env::var_os("HOME") {
+        Some(home) => {
+            let home = PathBuf::from(home);
+            vec![
+                home.join(".steam").join("steam"),
+                home.join(".local").join("share").join("Steam"),
+                home.join(".var")
+                    .join("app")
+                    .join("com.valvesoftware.Steam")
+                    .join(".local")
+                    .join("share")
+                    .join("Steam"),
+            ]
+        }
+        None => vec![],
+    }
+}
+
+/// Steam installations present on this machine.
+pub fn steam_roots() -> Vec<PathBuf> {
+    let mut roots: Vec<PathBuf> = vec![];
+    for candidate in steam_root_candidates() {
+        if let Ok(root) = candidate.canonicalize() {
+            if root.join("steamapps").is_dir() && !roots.contains(&root) {
+                roots.push(root);
+            }
+        }
+    }
+    roots
+}
+
+/// Library folders listed in a parsed libraryfolders.vdf, or `None` if it
+/// doesn't have a `libraryfolders` key. Older versions of Steam list each
+/// path directly under a numbered key, newer ones nest it in an object
+/// along with the apps installed there.
+fn library_folders(doc: &Value) -> Option<Vec<PathBuf>> {
+    let folders = doc.get("libraryfolders")?;
+    Some(
+        folders
+            .entries()
+            .iter()
+            .filter(|(key, _)| key.parse::<u32>().is_ok())
+            .filter_map(|(_, value)| match value {
+                Value::String(path) => Some(path.as_str()),
+                Value::Object(_) => value.get("path")?.as_str(),
+            })
+            .map(PathBuf::from)
+            .collect(),
+    )
+}
+
+/// Library folders listed in a Steam installation's libraryfolders.vdf.
+fn read_library_folders(root: &Path) -> Vec<PathBuf> {
+    let candidates = [
+        root.join("steamapps").join("libraryfolders.vdf"),
+        root.join("config").join("libraryfolders.vdf"),
+    ];
+    for p in candidates.iter() {
+        match vdf::read(p) {
+            Ok(doc) => {
+                if let Some(folders) = library_folders(&doc) {
+                    return folders;
+                }
+            }
+            Err(err) => debug!("Unable to read `{}`: {}", p.display(), err),
+        }
+    }
+    vec![]
+}
+
+/// Steam library folders, each containing a `steamapps` directory.
+pub fn steam_libraries() -> Vec<PathBuf> {
+    let mut libraries: Vec<PathBuf> = vec![];
+    for root in steam_roots() {
+        let mut candidates = vec![root.clone()];
+        candidates.append(&mut read_library_folders(&root));
+        for candidate in candidates {
+            if let Ok(library) = candidate.canonicalize() {
+                if library.join("steamapps").is_dir() && !libraries.contains(&library) {
+                    libraries.push(library);
+                }
+            }
+        }
+    }
+    libraries
+}
+
+/// A game installed through Steam.
+struct SteamApp {
+    library: PathBuf,
+    app_id: u32,
+    install_path: PathBuf,
+}
+
+/// Find a game in Steam's libraries by its app manifest.
+fn find_app(game: Game) -> Option<SteamApp> {
+    let libraries = steam_libraries();
+    for library in libraries.iter() {
+        let steamapps = library.join("steamapps");
+        for app_id in game.steam_app_ids() {
+            let manifest = steamapps.join(format!("appmanifest_{}.acf", app_id));
+            let install_dir = match vdf::read(&manifest) {
+                Ok(doc) => doc
+                    .get("AppState")
+                    .and_then(|state| state.get("installdir"))
+                    .and_then(|dir| dir.as_str())
+                    .map(String::from),
+                Err(_) => None,
+            };
+            if let Some(install_dir) = install_dir {
+                let install_path = steamapps.join("common").join(install_dir);
+                if install_path.is_dir() {
+                    return Some(SteamApp {
+                        library: library.clone(),
+                        app_id: *app_id,
+                        install_path,
+                    });
+                }
+            }
+        }
+    }
+    // Manifests go missing when libraries are copied between machines
+    // without Steam noticing, so fall back to the usual folder name.
+    libraries.into_iter().find_map(|library| {
+        let install_path = library
+            .join("steamapps")
+            .join("common")
+            .join(game.steam_folder_name());
+        if install_path.is_dir() {
+            Some(SteamApp {
+                library,
+                app_id: game.steam_app_ids()[0],
+                install_path,
+            })
+        } else {
+            None
+        }
+    })
+}
+
+/// Where Steam installed the game, if it did.
+pub fn steam_install_path(game: Game) -> Option<PathBuf> {
+    find_app(game).map(|app| app.install_path)
+}
+
+/// The game's Proton prefix. Steam creates it in the same library as the
+/// game the first time it is launched.
+pub fn proton_prefix(game: Game) -> Option<PathBuf> {
+    let app = find_app(game)?;
+    let prefix = app
+        .library
+        .join("steamapps")
+        .join("compatdata")
+        .join(app.app_id.to_string())
+        .join("pfx");
+    if prefix.is_dir() {
+        Some(prefix)
+    } else {
+        None
+    }
+}
+
+/// `%LOCALAPPDATA%` of the user Proton runs games as.
+pub fn prefix_local_app_data(prefix: &Path) -> PathBuf {
+    prefix
+        .join("drive_c")
+        .join("users")
+        .join("steamuser")
+        .join("AppData")
+        .join("Local")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_old_library_folders() {
+        let doc = vdf::parse(
+            r#""LibraryFolders"
+{
+	"TimeNextStatsReport"		"1642091519"
+	"ContentStatsID"		"-4012962939408178432"
+	"1"		"D:\\SteamLibrary"
+	"2"		"E:\\Games\\Steam"
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            library_folders(&doc).unwrap(),
+            [
+                PathBuf::from("D:\\SteamLibrary"),
+                PathBuf::from("E:\\Games\\Steam")
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_new_library_folders() {
+        let doc = vdf::parse(
+            r#""libraryfolders"
+{
+	"contentstatsid"		"-4012962939408178432"
+	"0"
+	{
+		"path"		"C:\\Program Files (x86)\\Steam"
+		"label"		""
+		"contentid"		"7521948498498176291"
+		"totalsize"		"0"
+		"apps"
+		{
+			"228980"		"394716542"
+			"489830"		"14872361512"
+		}
+	}
+	"1"
+	{
+		"path"		"/home/user/.local/share/Steam"
+		"label"		""
+		"apps"
+		{
+		}
+	}
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            library_folders(&doc).unwrap(),
+            [
+                PathBuf::from("C:\\Program Files (x86)\\Steam"),
+                PathBuf::from("/home/user/.local/share/Steam")
+            ]
+        );
+        let apps = doc
+            .get("libraryfolders")
+            .unwrap()
+            .get("0")
+            .unwrap()
+            .get("apps");
+        assert_eq!(
+            apps.unwrap().get("489830"),
+            Some(&Value::String(String::from("14872361512")))
+        );
+    }
+
+    #[test]
+    fn needs_a_libraryfolders_key() {
+        let doc = vdf::parse(r#""config" { "1" "D:\\SteamLibrary" }"#).unwrap();
+        assert_eq!(library_folders(&doc), None);
+    }
+}
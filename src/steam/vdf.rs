@@ -0,0 +1,192 @@
+//! A reader for Valve's text KeyValues format, used by Steam for
+//! `libraryfolders.vdf` and `appmanifest_*.acf` among others.
+
+use std::fs;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::iter::Peekable;
+use std::path::Path;
+use std::str::Chars;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Look up a key in an object. Keys are case insensitive.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.entries()
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v)
+    }
+
+    /// The key/value pairs of an object, in file order. Strings have none.
+    pub fn entries(&self) -> &[(String, Value)] {
+        match self {
+            Value::Object(entries) => entries,
+            Value::String(_) => &[],
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            Value::Object(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    String(String),
+    Open,
+    Close,
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid VDF: {}", message))
+}
+
+fn read_quoted(chars: &mut Peekable<Chars>) -> Result<String, Error> {
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(s),
+            Some('\\') => match chars.next() {
+                Some('n') => s.push('\n'),
+                Some('t') => s.push('\t'),
+                Some(c) => s.push(c),
+                None => return Err(invalid("unterminated string")),
+            },
+            Some(c) => s.push(c),
+            None => return Err(invalid("unterminated string")),
+        }
+    }
+}
+
+fn next_token(chars: &mut Peekable<Chars>) -> Result<Option<Token>, Error> {
+    loop {
+        match chars.peek().copied() {
+            None => return Ok(None),
+            Some(c) if c.is_whitespace() => {
+                chars.next();
+            }
+            Some('/') if chars.clone().nth(1) == Some('/') => {
+                // `//` comments run to the end of the line.
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            Some('[') => {
+                // Platform conditionals such as `[$WIN32]`. We don't evaluate them.
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                }
+            }
+            Some('{') => {
+                chars.next();
+                return Ok(Some(Token::Open));
+            }
+            Some('}') => {
+                chars.next();
+                return Ok(Some(Token::Close));
+            }
+            Some('"') => {
+                chars.next();
+                return Ok(Some(Token::String(read_quoted(chars)?)));
+            }
+            Some(_) => {
+                let mut s = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '{' || c == '}' || c == '"' {
+                        break;
+                    }
+                    s.push(c);
+                    chars.next();
+                }
+                return Ok(Some(Token::String(s)));
+            }
+        }
+    }
+}
+
+/// Read key/value pairs until the closing brace, or the end of the input
+/// at the top level.
+fn parse_object(chars: &mut Peekable<Chars>, top_level: bool) -> Result<Value, Error> {
+    let mut entries: Vec<(String, Value)> = vec![];
+    loop {
+        let key = match next_token(chars)? {
+            Some(Token::String(key)) => key,
+            Some(Token::Close) if !top_level => break,
+            None if top_level => break,
+            Some(Token::Close) => return Err(invalid("unexpected `}`")),
+            Some(Token::Open) => return Err(invalid("expected a key, found `{`")),
+            None => return Err(invalid("unexpected end of file")),
+        };
+        let value = match next_token(chars)? {
+            Some(Token::String(value)) => Value::String(value),
+            Some(Token::Open) => parse_object(chars, false)?,
+            Some(Token::Close) => return Err(invalid("expected a value, found `}`")),
+            None => return Err(invalid("unexpected end of file")),
+        };
+        entries.push((key, value));
+    }
+    Ok(Value::Object(entries))
+}
+
+/// Parse a KeyValues document. The result is an object holding the
+/// document's top level keys, usually just the one.
+pub fn parse(text: &str) -> Result<Value, Error> {
+    parse_object(&mut text.chars().peekable(), true)
+}
+
+pub fn read(p: &Path) -> Result<Value, Error> {
+    let bytes = fs::read(p)?;
+    parse(&String::from_utf8_lossy(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_escapes_comments_and_conditionals() {
+        let doc = parse(
+            r#"// Written by Steam
+"AppState"
+{
+	"installdir"		"Skyrim \"Special\" Edition"
+	"note"		"tab\there"
+	"LauncherPath"		"C:\\Steam\\steam.exe" [$WIN32]
+	unquoted	value
+}
+"#,
+        )
+        .unwrap();
+        let app = doc.get("appstate").unwrap();
+        assert_eq!(
+            app.get("InstallDir").and_then(Value::as_str),
+            Some("Skyrim \"Special\" Edition")
+        );
+        assert_eq!(app.get("note").and_then(Value::as_str), Some("tab\there"));
+        assert_eq!(
+            app.get("launcherpath").and_then(Value::as_str),
+            Some("C:\\Steam\\steam.exe")
+        );
+        assert_eq!(app.get("unquoted").and_then(Value::as_str), Some("value"));
+    }
+
+    #[test]
+    fn rejects_unbalanced_documents() {
+        assert!(parse(r#""libraryfolders" { "0" "C:\\Steam""#).is_err());
+        assert!(parse(r#""libraryfolders" }"#).is_err());
+        assert!(parse(r#""path" "unterminated"#).is_err());
+    }
+}
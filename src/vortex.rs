@@ -0,0 +1,86 @@
+//! Support for Vortex, which deploys mods into the Data directory from a
+//! staging folder and keeps a manifest of what it put there.
+
+use std::fs;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+
+use log::warn;
+use serde::Deserialize;
+
+use crate::find_file_ignore_case;
+
+/// A file Vortex deployed, as listed in its manifest.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeployedFile {
+    /// Path relative to the Data directory.
+    pub rel_path: String,
+    /// Name of the mod's folder in the staging folder.
+    pub source: String,
+}
+
+/// The `vortex.deployment.json` manifest Vortex writes into the
+/// directories it deploys to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VortexDeployment {
+    /// Where Vortex keeps each mod's files, one folder per mod.
+    pub staging_path: PathBuf,
+    #[serde(default)]
+    pub deployment_method: String,
+    #[serde(default)]
+    pub game_id: String,
+    #[serde(default)]
+    pub files: Vec<DeployedFile>,
+}
+
+pub const MANIFEST_NAME: &str = "vortex.deployment.json";
+
+impl VortexDeployment {
+    pub fn read(p: &Path) -> Result<Self, Error> {
+        let bytes = fs::read(p)?;
+        serde_json::from_slice(&bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Read the manifest from a Data directory, if Vortex manages it.
+    pub fn detect(data_path: &Path) -> Option<Self> {
+        let manifest = find_file_ignore_case(data_path, MANIFEST_NAME);
+        if manifest.is_file() {
+            Self::read(&manifest)
+                .map_err(|e| warn!("Unable to read `{}`: {}", manifest.display(), e))
+                .ok()
+        } else {
+            None
+        }
+    }
+
+    /// The mod a deployed file came from.
+    pub fn source_mod(&self, rel_path: &str) -> Option<&str> {
+        self.files
+            .iter()
+            .find(|f| f.rel_path.eq_ignore_ascii_case(rel_path))
+            .map(|f| f.source.as_str())
+    }
+
+    /// Prepare a mod folder in the staging folder to write into. Vortex
+    /// picks up new folders there as mods, so files written to it are
+    /// deployed and tracked like any other mod's rather than flagged as
+    /// external changes.
+    pub fn output_path(&self, mod_name: &str) -> Result<PathBuf, Error> {
+        if !self.staging_path.is_dir() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "Vortex staging folder `{}` does not exist",
+                    self.staging_path.display()
+                ),
+            ));
+        }
+        let path = self.staging_path.join(mod_name);
+        fs::create_dir_all(&path)?;
+        Ok(path)
+    }
+}
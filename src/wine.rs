@@ -0,0 +1,346 @@
+//! Reading the text registry hives Wine (and so Proton) keeps in the
+//! root of every prefix: `system.reg` for `HKEY_LOCAL_MACHINE` and
+//! `user.reg` for `HKEY_CURRENT_USER`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegValue {
+    String(String),
+    Dword(u32),
+    Binary(Vec<u8>),
+}
+
+impl RegValue {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            RegValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+/// A parsed registry hive. Like the real registry, key paths and value
+/// names are case insensitive.
+#[derive(Debug, Clone, Default)]
+pub struct RegistryHive {
+    keys: HashMap<String, HashMap<String, RegValue>>,
+}
+
+fn invalid(line: usize, message: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid registry hive (line {}): {}", line, message),
+    )
+}
+
+/// Key paths are written with doubled backslashes, and we look them up
+/// by their lowercase form with single backslashes.
+fn normalize_key(key: &str) -> String {
+    key.replace("\\\\", "\\").trim_matches('\\').to_lowercase()
+}
+
+/// Read a quoted string starting just after the opening quote, returning
+/// it along with whatever follows the closing quote.
+fn read_quoted(s: &str) -> Option<(String, &str)> {
+    let mut out = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((out, &s[i + 1..])),
+            '\\' => match chars.next()?.1 {
+                'n' => out.push('\n'),
+                'r' => out.push('\r'),
+                't' => out.push('\t'),
+                '0' => out.push('\0'),
+                'x' => {
+                    // Wine escapes anything outside of ASCII as `\x` and up
+                    // to four hex digits.
+                    let rest = &s[i + 2..];
+                    let len = rest
+                        .chars()
+                        .take(4)
+                        .take_while(|c| c.is_ascii_hexdigit())
+                        .count();
+                    let code = u32::from_str_radix(&rest[..len], 16).ok()?;
+                    out.push(char::from_u32(code)?);
+                    for _ in 0..len {
+                        chars.next();
+                    }
+                }
+                c => out.push(c),
+            },
+            c => out.push(c),
+        }
+    }
+    None
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    s.split(',')
+        .map(|b| b.trim())
+        .filter(|b| !b.is_empty())
+        .map(|b| u8::from_str_radix(b, 16).ok())
+        .collect()
+}
+
+fn parse_value(data: &str) -> Option<RegValue> {
+    if let Some(s) = data.strip_prefix('"') {
+        return read_quoted(s).map(|(s, _)| RegValue::String(s));
+    }
+    if let Some(s) = data.strip_prefix("str(2):\"") {
+        return read_quoted(s).map(|(s, _)| RegValue::String(s));
+    }
+    if let Some(dword) = data.strip_prefix("dword:") {
+        return u32::from_str_radix(dword.trim(), 16)
+            .ok()
+            .map(RegValue::Dword);
+    }
+    if data.starts_with("hex") {
+        let (_, bytes) = data.split_once(':')?;
+        return parse_hex(bytes).map(RegValue::Binary);
+    }
+    None
+}
+
+impl RegistryHive {
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut hive = RegistryHive::default();
+        let mut current: Option<String> = None;
+        let mut lines = text.lines().enumerate();
+        while let Some((number, line)) = lines.next() {
+            let mut line = String::from(line.trim_end());
+            // Long binary values are continued over several lines.
+            while line.ends_with('\\') && !line.ends_with("\\\\") {
+                line.pop();
+                match lines.next() {
+                    Some((_, next)) => line.push_str(next.trim()),
+                    None => break,
+                }
+            }
+            let line = line.trim_start();
+            if line.is_empty()
+                || line.starts_with(';')
+                || line.starts_with('#')
+                || line.starts_with("WINE REGISTRY")
+            {
+                continue;
+            }
+            if let Some(rest) = line.strip_prefix('[') {
+                let (key, _) = rest
+                    .rsplit_once(']')
+                    .ok_or_else(|| invalid(number + 1, "unterminated key"))?;
+                let key = normalize_key(key);
+                hive.keys.entry(key.clone()).or_default();
+                current = Some(key);
+                continue;
+            }
+            let key = match current {
+                Some(ref key) => key,
+                None => return Err(invalid(number + 1, "value outside of a key")),
+            };
+            let (name, data) = if let Some(data) = line.strip_prefix("@=") {
+                (String::new(), data)
+            } else if let Some(rest) = line.strip_prefix('"') {
+                let (name, rest) =
+                    read_quoted(rest).ok_or_else(|| invalid(number + 1, "unterminated name"))?;
+                match rest.strip_prefix('=') {
+                    Some(data) => (name, data),
+                    None => return Err(invalid(number + 1, "expected `=`")),
+                }
+            } else {
+                return Err(invalid(number + 1, "expected a value name"));
+            };
+            // Types we don't understand, like multi strings, are skipped.
+            if let Some(value) = parse_value(data) {
+                hive.keys
+                    .get_mut(key)
+                    .unwrap()
+                    .insert(name.to_lowercase(), value);
+            }
+        }
+        Ok(hive)
+    }
+
+    pub fn read(p: &Path) -> Result<Self, Error> {
+        let bytes = fs::read(p)?;
+        Self::parse(&String::from_utf8_lossy(&bytes))
+    }
+
+    /// Look up a value. `key` is relative to the hive's root, such as
+    /// `SOFTWARE\Bethesda Softworks\Oblivion` for `system.reg`.
+    pub fn get_value(&self, key: &str, name: &str) -> Option<&RegValue> {
+        self.keys
+            .get(&normalize_key(key))?
+            .get(&name.to_lowercase())
+    }
+}
+
+/// Translate a Windows path from inside a prefix to the real path,
+/// following the drive letter links in `dosdevices`.
+pub fn prefix_path(prefix: &Path, windows_path: &str) -> Option<PathBuf> {
+    let windows_path = windows_path.trim_end_matches('\\');
+    let (drive, rest) = windows_path.split_once(':')?;
+    let mut path = prefix
+        .join("dosdevices")
+        .join(format!("{}:", drive.to_lowercase()));
+    for component in rest.split('\\').filter(|c| !c.is_empty()) {
+        path.push(component);
+    }
+    Some(path)
+}
+
+/// Read a path from the `system.reg` hive of a Wine prefix, translated
+/// to a real path.
+pub fn prefix_registry_path(prefix: &Path, key: &str, name: &str) -> Result<String, String> {
+    let hive = RegistryHive::read(&prefix.join("system.reg")).map_err(|e| e.to_string())?;
+    let windows_path = hive
+        .get_value(key, name)
+        .and_then(|value| value.as_str())
+        .ok_or_else(|| format!("`{}` has no {}", key, name))?;
+    let path = prefix_path(prefix, windows_path)
+        .ok_or_else(|| format!("`{}` is not a valid path", windows_path))?;
+    let path = path.canonicalize().map_err(|e| e.to_string())?;
+    path.to_str()
+        .map(String::from)
+        .ok_or_else(|| format!("`{}` is not valid UTF-8", path.display()))
+}
+
+/// The same lookup as `registry_install_path`, against the `system.reg`
+/// hive of a Wine prefix.
+pub fn prefix_install_path(prefix: &Path, key: &str) -> Result<String, String> {
+    prefix_registry_path(prefix, key, "installed path")
+}
+
+/// `%LOCALAPPDATA%` of the current user inside a Wine prefix.
+pub fn prefix_local_app_data(prefix: &Path) -> Option<PathBuf> {
+    let user = std::env::var_os("USER")?;
+    Some(
+        prefix
+            .join("drive_c")
+            .join("users")
+            .join(user)
+            .join("AppData")
+            .join("Local"),
+    )
+}
+
+/// The default Wine prefix, as used when running games through Wine
+/// directly rather than through Proton.
+pub fn default_prefix() -> Option<PathBuf> {
+    std::env::var_os("WINEPREFIX")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".wine")))
+        .filter(|prefix| prefix.join("system.reg").is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYSTEM_REG: &str = r#"WINE REGISTRY Version 2
+;; All keys relative to \\Machine
+
+#arch=win64
+
+[Software\\Wow6432Node\\Bethesda Softworks\\Skyrim Special Edition] 1665483286
+#time=1d8dd6b2c1f8a4e
+"Installed Path"="C:\\Program Files (x86)\\Steam\\steamapps\\common\\Skyrim Special Edition\\"
+"Language"=str(2):"English"
+"Version"=dword:0000002c
+@="default"
+
+[Software\\Wine\\Fonts\\Replacements] 1665483286
+"Caf\xe9"="Caf\x00e9 \"quoted\""
+"Path"=hex(2):25,00,53,00,79,00,73,00,74,00,65,00,6d,00,52,00,6f,00,6f,00,74,\
+  00,25,00,00,00
+"#;
+
+    #[test]
+    fn reads_values_of_each_type() {
+        let hive = RegistryHive::parse(SYSTEM_REG).unwrap();
+        let key = r"Software\Wow6432Node\Bethesda Softworks\Skyrim Special Edition";
+        assert_eq!(
+            hive.get_value(key, "installed path")
+                .and_then(RegValue::as_str),
+            Some(r"C:\Program Files (x86)\Steam\steamapps\common\Skyrim Special Edition\")
+        );
+        assert_eq!(
+            hive.get_value(key, "Language").and_then(RegValue::as_str),
+            Some("English")
+        );
+        assert_eq!(hive.get_value(key, "Version"), Some(&RegValue::Dword(0x2c)));
+        assert_eq!(
+            hive.get_value(key, "").and_then(RegValue::as_str),
+            Some("default")
+        );
+    }
+
+    #[test]
+    fn key_paths_ignore_case_and_doubled_backslashes() {
+        let hive = RegistryHive::parse(SYSTEM_REG).unwrap();
+        for key in [
+            r"SOFTWARE\WOW6432NODE\BETHESDA SOFTWORKS\SKYRIM SPECIAL EDITION",
+            r"software\\wow6432node\\bethesda softworks\\skyrim special edition",
+            r"\Software\Wow6432Node\Bethesda Softworks\Skyrim Special Edition\",
+        ] {
+            assert!(hive.get_value(key, "Installed Path").is_some(), "{}", key);
+        }
+        assert_eq!(
+            hive.get_value(r"Software\Wow6432Node", "Installed Path"),
+            None
+        );
+    }
+
+    #[test]
+    fn reads_escapes_and_continued_hex() {
+        let hive = RegistryHive::parse(SYSTEM_REG).unwrap();
+        let key = r"Software\Wine\Fonts\Replacements";
+        assert_eq!(
+            hive.get_value(key, "café").and_then(RegValue::as_str),
+            Some("Café \"quoted\"")
+        );
+        let path: Vec<u8> = "%SystemRoot%\0"
+            .encode_utf16()
+            .flat_map(|c| c.to_le_bytes())
+            .collect();
+        assert_eq!(hive.get_value(key, "Path"), Some(&RegValue::Binary(path)));
+    }
+
+    #[test]
+    fn read_quoted_returns_the_rest() {
+        assert_eq!(
+            read_quoted(r#"a\\b\"c"=dword:1"#),
+            Some((String::from(r#"a\b"c"#), "=dword:1"))
+        );
+        assert_eq!(read_quoted("unterminated"), None);
+    }
+
+    #[test]
+    fn parses_hex_and_values() {
+        assert_eq!(
+            parse_hex("de,ad, be,ef,"),
+            Some(vec![0xde, 0xad, 0xbe, 0xef])
+        );
+        assert_eq!(parse_hex("de,zz"), None);
+        assert_eq!(parse_value("dword:zz"), None);
+        assert_eq!(parse_value("hex:01,02"), Some(RegValue::Binary(vec![1, 2])));
+        // Other hex types are read as raw bytes, other string types are skipped.
+        assert_eq!(
+            parse_value("hex(7):41,00,00,00"),
+            Some(RegValue::Binary(vec![0x41, 0, 0, 0]))
+        );
+        assert_eq!(parse_value("str(7):\"a\""), None);
+    }
+
+    #[test]
+    fn rejects_values_outside_of_a_key() {
+        assert!(RegistryHive::parse("\"Installed Path\"=\"C:\\\\\"\n").is_err());
+        assert!(RegistryHive::parse("[Software\\\\Bethesda\n").is_err());
+    }
+}